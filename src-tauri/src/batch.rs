@@ -0,0 +1,322 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cancel::CancelToken,
+    embeddings::EmbeddingCache,
+    moondream::{self, BatchedAnswer, Moondream, SamplingConfig, StoppingConfig},
+    preprocess::FrameSelection,
+    utils::{ImageInput, ImageSource},
+    Error, ErrorPayload, GenerationDetails,
+};
+
+/// How often a paused batch looks at its cancel token.
+const PAUSE_POLL: Duration = Duration::from_millis(200);
+
+/// The same prompts asked about many images.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchRequest {
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    /// Glob pattern adding every matching file, e.g. `/photos/**/*.jpg`.
+    pub glob: Option<String>,
+    pub prompts: Vec<String>,
+    pub output: Option<BatchOutput>,
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+    #[serde(default)]
+    pub stopping: StoppingConfig,
+    /// Images encoded and answered together. Larger batches make better use of
+    /// the hardware at the cost of memory.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+}
+
+fn default_batch_size() -> usize {
+    1
+}
+
+impl BatchRequest {
+    /// Listed paths followed by the glob matches, in order. Also checks the rest
+    /// of the request, so a bad one is rejected before any image is processed.
+    pub fn images(&self) -> Result<Vec<PathBuf>, Error> {
+        if self.prompts.is_empty() {
+            return Err(Error::InputError("No prompts to ask".to_string()));
+        }
+        if self.batch_size == 0 {
+            return Err(Error::InputError("batch_size must be positive".to_string()));
+        }
+        self.sampling.validate()?;
+        self.stopping.validate()?;
+        let mut images = self.paths.clone();
+        if let Some(pattern) = &self.glob {
+            let matches = glob::glob(pattern)
+                .map_err(|e| Error::InputError(format!("Invalid glob {pattern}: {e}")))?;
+            for path in matches {
+                let path = path.map_err(|e| Error::File {
+                    path: e.path().to_string_lossy().to_string(),
+                    source: e.into_error(),
+                })?;
+                if path.is_file() {
+                    images.push(path);
+                }
+            }
+        }
+        if images.is_empty() {
+            return Err(Error::InputError("No images to process".to_string()));
+        }
+        Ok(images)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    Jsonl,
+    Csv,
+}
+
+/// File the results are appended to as they come in.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BatchOutput {
+    pub path: PathBuf,
+    pub format: OutputFormat,
+}
+
+/// Answer to one prompt about one image.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchResult {
+    pub image: PathBuf,
+    pub prompt: String,
+    pub answer: Option<String>,
+    pub error: Option<ErrorPayload>,
+    pub details: Option<GenerationDetails>,
+}
+
+/// Message sent on the channel of a batch request, next to the queued, started,
+/// cancelled and error events every job gets.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BatchEvent {
+    Progress { done: usize, total: usize },
+    Result(BatchResult),
+    Paused,
+    Resumed,
+    Finished { done: usize, failed: usize },
+}
+
+/// Lets the frontend pause a running batch between two items.
+#[derive(Default)]
+pub struct BatchControl {
+    paused: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl BatchControl {
+    pub fn pause(&self) {
+        *self.paused.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = false;
+        self.changed.notify_all();
+    }
+
+    /// Blocks while paused. Returns whether it had to wait.
+    fn wait(&self, cancel: &CancelToken) -> Result<bool, Error> {
+        let mut paused = self.paused.lock().unwrap();
+        let mut waited = false;
+        while *paused {
+            waited = true;
+            cancel.check()?;
+            paused = self.changed.wait_timeout(paused, PAUSE_POLL).unwrap().0;
+        }
+        Ok(waited)
+    }
+}
+
+struct ResultWriter {
+    format: OutputFormat,
+    file: BufWriter<File>,
+    path: PathBuf,
+}
+
+impl ResultWriter {
+    fn create(output: &BatchOutput) -> Result<Self, Error> {
+        let path = output.path.clone();
+        let file = File::create(&path).map_err(Error::file(&path))?;
+        let mut writer = Self {
+            format: output.format,
+            file: BufWriter::new(file),
+            path,
+        };
+        if let OutputFormat::Csv = writer.format {
+            writer.write_line("image,prompt,answer,error")?;
+        }
+        Ok(writer)
+    }
+
+    fn write(&mut self, result: &BatchResult) -> Result<(), Error> {
+        let line = match self.format {
+            OutputFormat::Jsonl => serde_json::to_string(result)?,
+            OutputFormat::Csv => [
+                result.image.to_string_lossy().as_ref(),
+                result.prompt.as_str(),
+                result.answer.as_deref().unwrap_or_default(),
+                result
+                    .error
+                    .as_ref()
+                    .map(|e| e.message.as_str())
+                    .unwrap_or_default(),
+            ]
+            .map(csv_field)
+            .join(","),
+        };
+        self.write_line(&line)
+    }
+
+    /// Writes and flushes, so results survive a crash half way through.
+    fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        writeln!(self.file, "{line}")
+            .and_then(|_| self.file.flush())
+            .map_err(Error::file(&self.path))
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Asks every prompt about every image with a single model, `batch_size`
+/// images at a time. Failures of single items are reported and skipped; only
+/// cancellation stops the batch.
+pub fn run<F>(
+    request: &BatchRequest,
+    images: &[PathBuf],
+    moondream: &Moondream,
+    embeddings: &Mutex<EmbeddingCache>,
+    control: &BatchControl,
+    cancel: &CancelToken,
+    on_event: F,
+) -> Result<(), Error>
+where
+    F: Fn(BatchEvent) -> Result<(), Error>,
+{
+    let mut writer = request
+        .output
+        .as_ref()
+        .map(ResultWriter::create)
+        .transpose()?;
+    let total = images.len() * request.prompts.len();
+    let (mut done, mut failed) = (0, 0);
+    on_event(BatchEvent::Progress { done, total })?;
+    for chunk in images.chunks(request.batch_size) {
+        for prompt in &request.prompts {
+            if control.wait(cancel)? {
+                on_event(BatchEvent::Resumed)?;
+            }
+            cancel.check()?;
+            let answers = match answer(request, chunk, prompt, moondream, embeddings, cancel) {
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Ok(answers) => answers,
+                Err(e) => {
+                    tracing::error!("Batch of {} images failed: {:?}", chunk.len(), e);
+                    let payload = ErrorPayload::from(&e);
+                    chunk.iter().map(|_| Err(payload.clone())).collect()
+                }
+            };
+            for (image, answer) in chunk.iter().zip(answers) {
+                let result = match answer {
+                    Ok(answer) => BatchResult {
+                        image: image.clone(),
+                        prompt: prompt.clone(),
+                        answer: Some(answer.text),
+                        error: None,
+                        details: Some(answer.details),
+                    },
+                    Err(error) => {
+                        tracing::error!("Batch item {:?} failed: {}", image, error.message);
+                        failed += 1;
+                        BatchResult {
+                            image: image.clone(),
+                            prompt: prompt.clone(),
+                            answer: None,
+                            error: Some(error),
+                            details: None,
+                        }
+                    }
+                };
+                if let Some(writer) = &mut writer {
+                    writer.write(&result)?;
+                }
+                done += 1;
+                on_event(BatchEvent::Result(result))?;
+            }
+            on_event(BatchEvent::Progress { done, total })?;
+            if *control.paused.lock().unwrap() {
+                on_event(BatchEvent::Paused)?;
+            }
+        }
+    }
+    on_event(BatchEvent::Finished { done, failed })
+}
+
+/// Answers `prompt` about each image of `chunk`, encoding the images in one
+/// batch and decoding all their answers together. Later prompts about the same
+/// images hit the embedding cache.
+fn answer(
+    request: &BatchRequest,
+    chunk: &[PathBuf],
+    prompt: &str,
+    moondream: &Moondream,
+    embeddings: &Mutex<EmbeddingCache>,
+    cancel: &CancelToken,
+) -> Result<Vec<Result<BatchedAnswer, ErrorPayload>>, Error> {
+    let inputs: Vec<ImageInput> = chunk
+        .iter()
+        .map(|path| ImageInput {
+            source: ImageSource::Path { path: path.clone() },
+            frames: FrameSelection::default(),
+            crop: None,
+            tiling: None,
+        })
+        .collect();
+    let encoded = moondream::encode_images(&inputs, moondream, embeddings, cancel)?;
+    let loaded: Vec<bool> = encoded.iter().map(Result::is_ok).collect();
+    let (mut images, mut failures) = (vec![], vec![]);
+    for encoded in encoded {
+        match encoded {
+            Ok(image) => images.push(image),
+            Err(e) => failures.push(ErrorPayload::from(&e)),
+        }
+    }
+    let mut answers = moondream::generate_batch(
+        prompt,
+        images,
+        moondream,
+        &request.sampling,
+        &request.stopping,
+        cancel,
+    )?
+    .into_iter();
+    let mut failures = failures.into_iter();
+    Ok(loaded
+        .into_iter()
+        .map(|loaded| {
+            if loaded {
+                Ok(answers.next().expect("one answer per loaded image"))
+            } else {
+                Err(failures.next().expect("one error per failed image"))
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,456 @@
+//! Command line front end to the moondream pipeline, for scripts and servers
+//! where the app's window is not wanted.
+//!
+//! ```text
+//! moondream-cli ask --image photo.jpg --prompt "What is in the picture?"
+//! ```
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{de::DeserializeOwned, Serialize};
+use tauri_moondream_lib::{
+    cancel::CancelToken,
+    download::Downloader,
+    embeddings::EmbeddingCache,
+    moondream::{
+        self, ComputeDtype, ConfigVariant, ModelDescriptor, ModelSource, ModelStatus, Moondream,
+        SamplingConfig, StoppingConfig, WeightsFormat,
+    },
+    preprocess::{
+        Filter, FrameSelection, PreprocessSettings, Region, ResizeStrategy, TilingConfig,
+    },
+    utils::{self, CpuFeatures, DeviceSetting, ImageInput, ImageSource},
+    GenerationDetails, ImagePart,
+};
+
+#[derive(Parser)]
+#[command(name = "moondream-cli", version, about = "Ask moondream about images")]
+struct Cli {
+    /// Log debug output to stderr.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Answer a question about an image.
+    Ask(AskArgs),
+    /// Download the model files into the hub cache.
+    Download(ModelArgs),
+    /// List the devices the model can run on.
+    Devices,
+}
+
+#[derive(Args)]
+struct AskArgs {
+    /// Image file to look at.
+    #[arg(long)]
+    image: PathBuf,
+    #[arg(long)]
+    prompt: String,
+    /// Frame of an animation, or page of a TIFF, to look at.
+    #[arg(long, conflicts_with = "sample_frames")]
+    frame: Option<usize>,
+    /// Answer about this many frames spread over an animation.
+    #[arg(long)]
+    sample_frames: Option<usize>,
+    /// Only look at `x,y,width,height` of the image.
+    #[arg(long, value_parser = parse_region)]
+    crop: Option<Region>,
+    /// Split large images into tiles and answer about each of them.
+    #[arg(long)]
+    tile: bool,
+    #[arg(long, requires = "tile")]
+    tile_size: Option<u32>,
+    #[arg(long, requires = "tile")]
+    tile_overlap: Option<u32>,
+    #[arg(long, requires = "tile")]
+    max_tiles: Option<usize>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    /// Fail instead of downloading missing model files.
+    #[arg(long)]
+    offline: bool,
+    #[command(flatten)]
+    model: ModelArgs,
+    #[command(flatten)]
+    runtime: RuntimeArgs,
+    #[command(flatten)]
+    sampling: SamplingArgs,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Stream the answer as it is generated.
+    Text,
+    /// Print the answers and their details once done.
+    Json,
+    /// Stream every generated token as a JSON line.
+    Jsonl,
+}
+
+/// Which model to load, defaulting to [`ModelDescriptor::default`].
+#[derive(Args)]
+struct ModelArgs {
+    /// Hub repository of the model.
+    #[arg(long)]
+    repo: Option<String>,
+    /// Branch, tag or commit on the hub.
+    #[arg(long)]
+    revision: Option<String>,
+    /// Load the model files from this directory instead of the hub.
+    #[arg(long)]
+    model_dir: Option<PathBuf>,
+    #[arg(long)]
+    weights_file: Option<String>,
+    /// `safetensors` or `gguf`.
+    #[arg(long, value_parser = parse_setting::<WeightsFormat>)]
+    weights_format: Option<WeightsFormat>,
+    #[arg(long)]
+    tokenizer_file: Option<String>,
+    #[arg(long, value_parser = parse_setting::<ConfigVariant>)]
+    config: Option<ConfigVariant>,
+    /// Hub to download from, e.g. a mirror.
+    #[arg(long)]
+    hub_endpoint: Option<String>,
+    /// Hub cache directory, the shared Hugging Face cache by default.
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+}
+
+impl ModelArgs {
+    fn descriptor(&self) -> ModelDescriptor {
+        let mut descriptor = ModelDescriptor::default();
+        if let Some(dir) = &self.model_dir {
+            descriptor.source = ModelSource::Local { dir: dir.clone() };
+        }
+        if let Some(repo) = &self.repo {
+            descriptor.repo_id = repo.clone();
+        }
+        descriptor.revision = self.revision.clone();
+        if let Some(weights_file) = &self.weights_file {
+            descriptor.weights_file = weights_file.clone();
+        }
+        if let Some(weights_format) = self.weights_format {
+            descriptor.weights_format = weights_format;
+        }
+        if let Some(tokenizer_file) = &self.tokenizer_file {
+            descriptor.tokenizer_file = tokenizer_file.clone();
+        }
+        if let Some(config) = self.config {
+            descriptor.config = config;
+        }
+        descriptor
+    }
+
+    fn cache(&self) -> hf_hub::Cache {
+        match &self.cache_dir {
+            Some(dir) => hf_hub::Cache::new(dir.clone()),
+            None => hf_hub::Cache::default(),
+        }
+    }
+
+    fn downloader(&self) -> Downloader {
+        Downloader::new(self.cache(), self.hub_endpoint.clone())
+    }
+}
+
+#[derive(Args)]
+struct RuntimeArgs {
+    /// `auto`, `cpu`, `cuda:N` or `metal`.
+    #[arg(long, default_value_t = DeviceSetting::Auto)]
+    device: DeviceSetting,
+    /// `f32`, `bf16` or `f16`, picked for the device by default.
+    #[arg(long, value_parser = parse_setting::<ComputeDtype>)]
+    dtype: Option<ComputeDtype>,
+    /// `fill`, `letterbox` or `stretch`.
+    #[arg(long, value_parser = parse_setting::<ResizeStrategy>)]
+    resize: Option<ResizeStrategy>,
+    /// `nearest`, `triangle`, `catmull_rom`, `gaussian` or `lanczos3`.
+    #[arg(long, value_parser = parse_setting::<Filter>)]
+    filter: Option<Filter>,
+}
+
+impl RuntimeArgs {
+    fn preprocess(&self) -> PreprocessSettings {
+        let defaults = PreprocessSettings::default();
+        PreprocessSettings {
+            resize: self.resize.unwrap_or(defaults.resize),
+            filter: self.filter.unwrap_or(defaults.filter),
+        }
+    }
+}
+
+/// Sampling and stopping options, defaulting to greedy decoding.
+#[derive(Args)]
+struct SamplingArgs {
+    /// Softmax temperature, unset or `0` picks the most likely token.
+    #[arg(long)]
+    temperature: Option<f64>,
+    #[arg(long)]
+    top_p: Option<f64>,
+    #[arg(long)]
+    top_k: Option<usize>,
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Penalty on tokens already generated, `1` disables it.
+    #[arg(long)]
+    repeat_penalty: Option<f32>,
+    #[arg(long)]
+    repeat_last_n: Option<usize>,
+    #[arg(long)]
+    max_new_tokens: Option<usize>,
+    /// End the answer where this string appears, can be repeated.
+    #[arg(long)]
+    stop: Vec<String>,
+}
+
+impl SamplingArgs {
+    fn sampling(&self) -> SamplingConfig {
+        let defaults = SamplingConfig::default();
+        SamplingConfig {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            seed: self.seed.unwrap_or(defaults.seed),
+            repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+        }
+    }
+
+    fn stopping(&self) -> StoppingConfig {
+        let defaults = StoppingConfig::default();
+        StoppingConfig {
+            max_new_tokens: self.max_new_tokens.unwrap_or(defaults.max_new_tokens),
+            stop: self.stop.clone(),
+        }
+    }
+}
+
+/// Parses a value the way the settings file spells it, e.g. `bf16`.
+fn parse_setting<T: DeserializeOwned>(s: &str) -> Result<T, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_string())).map_err(|e| e.to_string())
+}
+
+fn parse_region(s: &str) -> Result<Region, String> {
+    let values: Vec<u32> = s
+        .split(',')
+        .map(|value| value.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("{e}"))?;
+    match values[..] {
+        [x, y, width, height] => Ok(Region {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => Err("Expected x,y,width,height".to_string()),
+    }
+}
+
+/// Everything `ask` prints with `--output json`.
+#[derive(Serialize)]
+struct AskOutput {
+    image: PathBuf,
+    prompt: String,
+    answers: Vec<Answer>,
+}
+
+#[derive(Serialize)]
+struct Answer {
+    part: ImagePart,
+    answer: String,
+    details: Option<GenerationDetails>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(if cli.verbose {
+            tracing::Level::DEBUG
+        } else {
+            tracing::Level::WARN
+        })
+        .init();
+    match cli.command {
+        Command::Ask(args) => ask(args),
+        Command::Download(model) => download(&model, &model.descriptor()),
+        Command::Devices => devices(),
+    }
+}
+
+fn ask(args: AskArgs) -> Result<()> {
+    let descriptor = args.model.descriptor();
+    if descriptor.source == ModelSource::Hub {
+        let missing = args.model.downloader().missing_files(&descriptor);
+        if !missing.is_empty() {
+            if args.offline {
+                bail!("Model files not downloaded: {}", missing.join(", "));
+            }
+            download(&args.model, &descriptor)?;
+        }
+    }
+
+    let device = utils::device(args.runtime.device)?;
+    let dtype = args
+        .runtime
+        .dtype
+        .unwrap_or_else(|| ComputeDtype::for_device(&device));
+    let cancel = CancelToken::default();
+    let moondream = Moondream::load(
+        &descriptor,
+        &device,
+        dtype.dtype(),
+        args.runtime.preprocess(),
+        &args.model.cache(),
+        &cancel,
+        |status| {
+            if let ModelStatus::Loading { message, .. } = status {
+                tracing::info!("{}", message);
+            }
+        },
+    )?;
+
+    let input = ImageInput {
+        source: ImageSource::Path {
+            path: args.image.clone(),
+        },
+        frames: match (args.frame, args.sample_frames) {
+            (_, Some(count)) => FrameSelection::Sample { count },
+            (Some(index), None) => FrameSelection::Frame { index },
+            (None, None) => FrameSelection::default(),
+        },
+        crop: args.crop,
+        tiling: args.tile.then(|| {
+            let defaults = TilingConfig::default();
+            TilingConfig {
+                tile_size: args.tile_size.unwrap_or(defaults.tile_size),
+                overlap: args.tile_overlap.unwrap_or(defaults.overlap),
+                max_tiles: args.max_tiles.unwrap_or(defaults.max_tiles),
+                aggregate: false,
+            }
+        }),
+    };
+    let embeddings = Mutex::new(EmbeddingCache::new(1, None, 0));
+    let images = moondream::encode_image(&input, &moondream, &embeddings, &cancel)?;
+    let (sampling, stopping) = (args.sampling.sampling(), args.sampling.stopping());
+
+    let mut stdout = std::io::stdout().lock();
+    let count = images.len();
+    let mut answers = Vec::with_capacity(count);
+    for (index, image) in images.into_iter().enumerate() {
+        let part = ImagePart::new(image.frame(), image.tile(), index, count);
+        if count > 1 && args.output == OutputFormat::Text {
+            match image.tile() {
+                Some(tile) => writeln!(stdout, "[frame {}, tile {:?}]", image.frame(), tile)?,
+                None => writeln!(stdout, "[frame {}]", image.frame())?,
+            }
+        }
+        let mut text_model = moondream.text_model();
+        let mut pipeline = moondream::build_pipeline(
+            &args.prompt,
+            image,
+            &moondream,
+            &mut text_model,
+            &sampling,
+            &stopping,
+            &cancel,
+        )?;
+        if count > 1 {
+            pipeline.set_part(part.clone());
+        }
+        let mut details = None;
+        for generation in pipeline.iter() {
+            let generation = generation?;
+            match args.output {
+                OutputFormat::Text if !generation.token().is_special() => {
+                    write!(stdout, "{}", generation.token().text())?;
+                    stdout.flush()?;
+                }
+                OutputFormat::Jsonl => {
+                    serde_json::to_writer(&mut stdout, &generation)?;
+                    writeln!(stdout)?;
+                    stdout.flush()?;
+                }
+                _ => {}
+            }
+            details = generation.details().cloned().or(details);
+        }
+        if args.output == OutputFormat::Text {
+            writeln!(stdout)?;
+        }
+        answers.push(Answer {
+            part,
+            answer: pipeline.generated_text()?,
+            details,
+        });
+    }
+
+    if args.output == OutputFormat::Json {
+        let output = AskOutput {
+            image: args.image,
+            prompt: args.prompt,
+            answers,
+        };
+        serde_json::to_writer_pretty(&mut stdout, &output)?;
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+/// Downloads the files of `descriptor` missing from the cache, reporting
+/// progress on stderr so stdout only carries answers.
+fn download(model: &ModelArgs, descriptor: &ModelDescriptor) -> Result<()> {
+    if descriptor.source != ModelSource::Hub {
+        bail!("Only hub models can be downloaded");
+    }
+    let downloader = model.downloader();
+    if downloader.missing_files(descriptor).is_empty() {
+        return Ok(());
+    }
+    downloader.download(descriptor, &CancelToken::default(), |progress| {
+        let mut stderr = std::io::stderr().lock();
+        let _ = match progress.total {
+            Some(total) => write!(
+                stderr,
+                "\rDownloading {} ({}/{}): {:.0}%",
+                progress.file,
+                progress.file_index + 1,
+                progress.file_count,
+                progress.downloaded as f64 / total.max(1) as f64 * 100.0
+            ),
+            None => write!(
+                stderr,
+                "\rDownloading {} ({}/{}): {} bytes",
+                progress.file,
+                progress.file_index + 1,
+                progress.file_count,
+                progress.downloaded
+            ),
+        };
+    })?;
+    eprintln!();
+    Ok(())
+}
+
+fn devices() -> Result<()> {
+    for device in utils::available_devices() {
+        println!("{device}");
+    }
+    let CpuFeatures {
+        avx,
+        neon,
+        simd128,
+        f16c,
+    } = CpuFeatures::detect();
+    println!("cpu features: avx={avx} neon={neon} simd128={simd128} f16c={f16c}");
+    Ok(())
+}
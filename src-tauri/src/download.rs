@@ -0,0 +1,646 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{cancel::CancelToken, moondream::ModelDescriptor, Error};
+
+pub const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Progress of a model download, emitted to the frontend as `download-progress`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadProgress {
+    pub file: String,
+    pub downloaded: u64,
+    /// `None` when the server did not report a size.
+    pub total: Option<u64>,
+    /// Zero based index of `file` among the files being downloaded.
+    pub file_index: usize,
+    pub file_count: usize,
+}
+
+/// What the hub reports about a file before it is downloaded.
+struct FileMetadata {
+    commit: String,
+    /// Blob name in the cache: the sha256 for LFS files, the git hash otherwise.
+    etag: String,
+    /// Only LFS files come with a sha256 we can check.
+    sha256: Option<String>,
+    size: Option<u64>,
+}
+
+/// Downloads model files from the hub into the hf_hub cache layout, so that a
+/// later `Moondream::load` finds them there. Partial downloads are resumed and
+/// LFS files are checked against their sha256.
+pub struct Downloader {
+    agent: ureq::Agent,
+    endpoint: String,
+    cache: hf_hub::Cache,
+}
+
+impl Downloader {
+    pub fn new(cache: hf_hub::Cache, endpoint: Option<String>) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .redirects(0)
+            .timeout_connect(Duration::from_secs(30))
+            .build();
+        Self {
+            agent,
+            endpoint: endpoint
+                .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            cache,
+        }
+    }
+
+    /// Files of `descriptor` that are not in the cache yet.
+    pub fn missing_files(&self, descriptor: &ModelDescriptor) -> Vec<String> {
+        let repo = self.cache.repo(descriptor.repo());
+        [&descriptor.weights_file, &descriptor.tokenizer_file]
+            .into_iter()
+            .filter(|file| repo.get(file).is_none())
+            .cloned()
+            .collect()
+    }
+
+    pub fn download<F>(
+        &self,
+        descriptor: &ModelDescriptor,
+        cancel: &CancelToken,
+        on_progress: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(DownloadProgress),
+    {
+        let files = self.missing_files(descriptor);
+        let file_count = files.len();
+        for (file_index, file) in files.iter().enumerate() {
+            cancel.check()?;
+            self.download_file(descriptor, file, cancel, |downloaded, total| {
+                on_progress(DownloadProgress {
+                    file: file.clone(),
+                    downloaded,
+                    total,
+                    file_index,
+                    file_count,
+                })
+            })?;
+        }
+        Ok(())
+    }
+
+    fn url(&self, descriptor: &ModelDescriptor, file: &str) -> String {
+        let revision = descriptor.revision.as_deref().unwrap_or("main");
+        format!(
+            "{}/{}/resolve/{}/{}",
+            self.endpoint, descriptor.repo_id, revision, file
+        )
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+        match self.cache.token() {
+            Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    fn metadata(&self, url: &str, revision: &str) -> Result<FileMetadata, Error> {
+        let response = self
+            .request("HEAD", url)
+            .call()
+            .map_err(download_error(url))?;
+        let header = |name: &str| response.header(name).map(|value| value.to_string());
+        let linked_etag = header("x-linked-etag").map(|etag| normalize_etag(&etag));
+        let etag = linked_etag
+            .clone()
+            .or_else(|| header("etag").map(|etag| normalize_etag(&etag)))
+            .ok_or_else(|| Error::Download {
+                url: url.to_string(),
+                message: "Missing etag".to_string(),
+            })?;
+        let size = header("x-linked-size")
+            .or_else(|| header("content-length"))
+            .and_then(|size| size.parse().ok());
+        Ok(FileMetadata {
+            commit: header("x-repo-commit").unwrap_or_else(|| revision.to_string()),
+            etag,
+            sha256: linked_etag,
+            size,
+        })
+    }
+
+    fn download_file<F>(
+        &self,
+        descriptor: &ModelDescriptor,
+        file: &str,
+        cancel: &CancelToken,
+        on_progress: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(u64, Option<u64>),
+    {
+        let url = self.url(descriptor, file);
+        let revision = descriptor.revision.as_deref().unwrap_or("main");
+        let metadata = self.metadata(&url, revision)?;
+        let repo_dir = self
+            .cache
+            .path()
+            .join(format!("models--{}", descriptor.repo_id.replace('/', "--")));
+        let blob = repo_dir.join("blobs").join(&metadata.etag);
+        if !blob.exists() {
+            self.fetch_blob(&url, &blob, &metadata, cancel, on_progress)?;
+        }
+        let pointer = repo_dir.join("snapshots").join(&metadata.commit).join(file);
+        link(&blob, &pointer)?;
+        let refs = repo_dir.join("refs");
+        std::fs::create_dir_all(&refs).map_err(Error::file(&refs))?;
+        let ref_path = refs.join(revision);
+        std::fs::write(&ref_path, &metadata.commit).map_err(Error::file(&ref_path))?;
+        tracing::info!("Downloaded {} to {:?}", file, pointer);
+        Ok(())
+    }
+
+    fn fetch_blob<F>(
+        &self,
+        url: &str,
+        blob: &Path,
+        metadata: &FileMetadata,
+        cancel: &CancelToken,
+        on_progress: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(u64, Option<u64>),
+    {
+        let partial = blob.with_extension("part");
+        if let Some(dir) = partial.parent() {
+            std::fs::create_dir_all(dir).map_err(Error::file(dir))?;
+        }
+        let mut downloaded = partial.metadata().map(|m| m.len()).unwrap_or(0);
+        // Longer than the file, so not a prefix of it.
+        if metadata.size.is_some_and(|size| downloaded > size) {
+            downloaded = 0;
+        }
+        // A crash between the last write and the rename leaves a complete partial
+        // file, which the checksum below vouches for.
+        if downloaded == 0 || metadata.size != Some(downloaded) {
+            let mut request = self.request("GET", url);
+            if downloaded > 0 {
+                tracing::debug!("Resuming {} at {} bytes", url, downloaded);
+                request = request.set("Range", &format!("bytes={downloaded}-"));
+            }
+            let response = self.follow(request, url)?;
+            if response.status() != 416 {
+                // A server ignoring the range sends the whole file again.
+                if downloaded > 0 && response.status() != 206 {
+                    downloaded = 0;
+                }
+                stream(
+                    response,
+                    url,
+                    &partial,
+                    downloaded,
+                    metadata.size,
+                    cancel,
+                    on_progress,
+                )?;
+            }
+        } else {
+            on_progress(downloaded, metadata.size);
+        }
+
+        let (expected, actual) = match &metadata.sha256 {
+            Some(expected) => (expected, sha256(&partial)?),
+            // Files outside LFS are named by their git blob hash.
+            None => (&metadata.etag, git_blob_sha1(&partial)?),
+        };
+        if &actual != expected {
+            std::fs::remove_file(&partial).map_err(Error::file(&partial))?;
+            return Err(Error::ChecksumMismatch {
+                file: blob.to_string_lossy().to_string(),
+                expected: expected.clone(),
+                actual,
+            });
+        }
+        std::fs::rename(&partial, blob).map_err(Error::file(blob))?;
+        Ok(())
+    }
+
+    /// Sends `request`, following redirects by hand so the auth header is only
+    /// sent to the hub and not to the storage it redirects to.
+    fn follow(&self, request: ureq::Request, url: &str) -> Result<ureq::Response, Error> {
+        let range = request.header("Range").map(|range| range.to_string());
+        let mut response = call(request, url)?;
+        for _ in 0..5 {
+            if !(300..400).contains(&response.status()) {
+                return Ok(response);
+            }
+            let location = response.header("location").ok_or_else(|| Error::Download {
+                url: url.to_string(),
+                message: "Redirect without location".to_string(),
+            })?;
+            let location = if location.starts_with('/') {
+                format!("{}{}", self.endpoint, location)
+            } else {
+                location.to_string()
+            };
+            let mut request = self.agent.get(&location);
+            if let Some(range) = &range {
+                request = request.set("Range", range);
+            }
+            response = call(request, &location)?;
+        }
+        Err(Error::Download {
+            url: url.to_string(),
+            message: "Too many redirects".to_string(),
+        })
+    }
+}
+
+/// Appends the body of `response` to the partial file, which already holds
+/// `downloaded` bytes.
+fn stream<F>(
+    response: ureq::Response,
+    url: &str,
+    partial: &Path,
+    mut downloaded: u64,
+    size: Option<u64>,
+    cancel: &CancelToken,
+    on_progress: F,
+) -> Result<(), Error>
+where
+    F: Fn(u64, Option<u64>),
+{
+    let mut output = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(downloaded > 0)
+        .truncate(downloaded == 0)
+        .open(partial)
+        .map_err(Error::file(partial))?;
+    let mut reader = response.into_reader();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    on_progress(downloaded, size);
+    loop {
+        cancel.check()?;
+        let read = reader.read(&mut buffer).map_err(download_io_error(url))?;
+        if read == 0 {
+            break;
+        }
+        output
+            .write_all(&buffer[..read])
+            .map_err(Error::file(partial))?;
+        downloaded += read as u64;
+        on_progress(downloaded, size);
+    }
+    output.flush().map_err(Error::file(partial))
+}
+
+/// Sends `request`, passing a 416 through as the answer to resuming a file that
+/// is already complete.
+fn call(request: ureq::Request, url: &str) -> Result<ureq::Response, Error> {
+    match request.call() {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(416, response)) => Ok(response),
+        Err(e) => Err(download_error(url)(e)),
+    }
+}
+
+fn normalize_etag(etag: &str) -> String {
+    etag.trim_start_matches("W/").trim_matches('"').to_string()
+}
+
+fn sha256(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path).map_err(Error::file(path))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(Error::file(path))?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Hash git gives the file as a blob, the etag of files outside LFS.
+fn git_blob_sha1(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path).map_err(Error::file(path))?;
+    let len = file.metadata().map_err(Error::file(path))?.len();
+    let mut hasher = Sha1::new();
+    hasher.update(format!("blob {len}\0"));
+    std::io::copy(&mut file, &mut hasher).map_err(Error::file(path))?;
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Points the snapshot entry at the blob, copying when links are unavailable.
+fn link(blob: &Path, pointer: &Path) -> Result<(), Error> {
+    if let Some(dir) = pointer.parent() {
+        std::fs::create_dir_all(dir).map_err(Error::file(dir))?;
+    }
+    if pointer.exists() {
+        return Ok(());
+    }
+    if std::fs::hard_link(blob, pointer).is_err() {
+        std::fs::copy(blob, pointer).map_err(Error::file(pointer))?;
+    }
+    Ok(())
+}
+
+fn download_error(url: &str) -> impl FnOnce(ureq::Error) -> Error {
+    let url = url.to_string();
+    move |e| Error::Download {
+        url,
+        message: e.to_string(),
+    }
+}
+
+fn download_io_error(url: &str) -> impl FnOnce(std::io::Error) -> Error {
+    let url = url.to_string();
+    move |e| Error::Download {
+        url,
+        message: e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::content_hash;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    const CONTENT: &[u8] = b"moondream weights, or something like them";
+    const COMMIT: &str = "0123abcd";
+    const FILE: &str = "model.safetensors";
+
+    /// Status, headers and body of a stand-in response.
+    type Reply = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+    /// Method, path and range start of a request the stand-in received.
+    type Request = (String, String, Option<u64>);
+
+    /// Minimal HTTP server standing in for the hub and the storage it redirects
+    /// to, one connection at a time.
+    struct Hub {
+        endpoint: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl Hub {
+        fn start<F>(serve: F) -> Self
+        where
+            F: Fn(&str, &str, Option<u64>) -> Reply + Send + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(vec![]));
+            let log = requests.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(mut stream) = stream else {
+                        break;
+                    };
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).is_err() {
+                        continue;
+                    }
+                    let mut parts = line.split_whitespace();
+                    let method = parts.next().unwrap_or_default().to_string();
+                    let path = parts.next().unwrap_or_default().to_string();
+                    let mut range = None;
+                    loop {
+                        let mut header = String::new();
+                        if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':') {
+                            if name.eq_ignore_ascii_case("range") {
+                                range = value
+                                    .trim()
+                                    .trim_start_matches("bytes=")
+                                    .trim_end_matches('-')
+                                    .parse()
+                                    .ok();
+                            }
+                        }
+                    }
+                    log.lock()
+                        .unwrap()
+                        .push((method.clone(), path.clone(), range));
+                    let (status, headers, body) = serve(&method, &path, range);
+                    let mut head = format!("HTTP/1.1 {status} Stand-in\r\nConnection: close\r\n");
+                    for (name, value) in headers {
+                        head.push_str(&format!("{name}: {value}\r\n"));
+                    }
+                    if method != "HEAD" {
+                        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+                    }
+                    head.push_str("\r\n");
+                    let _ = stream.write_all(head.as_bytes());
+                    if method != "HEAD" {
+                        let _ = stream.write_all(&body);
+                    }
+                }
+            });
+            Self { endpoint, requests }
+        }
+
+        /// A hub serving `CONTENT` from its storage, announcing `sha256` and,
+        /// when set, the size of the file.
+        fn serving(sha256: String, size: Option<usize>) -> Self {
+            Self::serving_with(Some(sha256), "pointer".to_string(), size)
+        }
+
+        /// A hub serving `CONTENT` as a plain git file, known by its `etag`.
+        fn serving_outside_lfs(etag: String) -> Self {
+            Self::serving_with(None, etag, Some(CONTENT.len()))
+        }
+
+        fn serving_with(linked_etag: Option<String>, etag: String, size: Option<usize>) -> Self {
+            Self::start(move |method, path, range| {
+                let resolve = format!("/test/model/resolve/main/{FILE}");
+                match method {
+                    "HEAD" if path == resolve => {
+                        let mut headers = vec![
+                            ("x-repo-commit", COMMIT.to_string()),
+                            ("etag", format!("\"{etag}\"")),
+                        ];
+                        let size_header = match &linked_etag {
+                            Some(linked_etag) => {
+                                headers.push(("x-linked-etag", format!("\"{linked_etag}\"")));
+                                "x-linked-size"
+                            }
+                            None => "content-length",
+                        };
+                        if let Some(size) = size {
+                            headers.push((size_header, size.to_string()));
+                        }
+                        (200, headers, vec![])
+                    }
+                    "GET" if path == resolve => {
+                        (302, vec![("location", "/storage/blob".to_string())], vec![])
+                    }
+                    "GET" if path == "/storage/blob" => match range {
+                        Some(start) if start as usize >= CONTENT.len() => (416, vec![], vec![]),
+                        Some(start) => (206, vec![], CONTENT[start as usize..].to_vec()),
+                        None => (200, vec![], CONTENT.to_vec()),
+                    },
+                    _ => (404, vec![], vec![]),
+                }
+            })
+        }
+
+        fn storage_requests(&self) -> Vec<Option<u64>> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(method, path, _)| method == "GET" && path == "/storage/blob")
+                .map(|(_, _, range)| *range)
+                .collect()
+        }
+    }
+
+    struct Fixture {
+        cache_dir: PathBuf,
+        downloader: Downloader,
+        descriptor: ModelDescriptor,
+    }
+
+    impl Fixture {
+        fn new(name: &str, hub: &Hub) -> Self {
+            let cache_dir = std::env::temp_dir().join(format!(
+                "moondream-download-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&cache_dir);
+            let cache = hf_hub::Cache::new(cache_dir.clone());
+            Self {
+                downloader: Downloader::new(cache, Some(hub.endpoint.clone())),
+                descriptor: ModelDescriptor {
+                    repo_id: "test/model".to_string(),
+                    weights_file: FILE.to_string(),
+                    ..Default::default()
+                },
+                cache_dir,
+            }
+        }
+
+        fn blobs(&self) -> PathBuf {
+            self.cache_dir.join("models--test--model").join("blobs")
+        }
+
+        fn write_partial(&self, content: &[u8]) {
+            std::fs::create_dir_all(self.blobs()).unwrap();
+            let partial = self.blobs().join(format!("{}.part", content_hash(CONTENT)));
+            std::fs::write(partial, content).unwrap();
+        }
+
+        fn download(&self) -> Result<(), Error> {
+            self.downloader.download_file(
+                &self.descriptor,
+                FILE,
+                &CancelToken::default(),
+                |_, _| {},
+            )
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            let pointer = self
+                .cache_dir
+                .join("models--test--model")
+                .join("snapshots")
+                .join(COMMIT)
+                .join(FILE);
+            std::fs::read(pointer).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.cache_dir);
+        }
+    }
+
+    #[test]
+    fn downloads_through_a_redirect() {
+        let hub = Hub::serving(content_hash(CONTENT), Some(CONTENT.len()));
+        let fixture = Fixture::new("redirect", &hub);
+        fixture.download().unwrap();
+        assert_eq!(fixture.snapshot(), CONTENT);
+        assert_eq!(hub.storage_requests(), vec![None]);
+    }
+
+    #[test]
+    fn resumes_a_partial_download() {
+        let hub = Hub::serving(content_hash(CONTENT), Some(CONTENT.len()));
+        let fixture = Fixture::new("resume", &hub);
+        fixture.write_partial(&CONTENT[..10]);
+        fixture.download().unwrap();
+        assert_eq!(fixture.snapshot(), CONTENT);
+        assert_eq!(hub.storage_requests(), vec![Some(10)]);
+    }
+
+    #[test]
+    fn finishes_a_complete_partial_without_fetching() {
+        let hub = Hub::serving(content_hash(CONTENT), Some(CONTENT.len()));
+        let fixture = Fixture::new("complete", &hub);
+        fixture.write_partial(CONTENT);
+        fixture.download().unwrap();
+        assert_eq!(fixture.snapshot(), CONTENT);
+        assert!(hub.storage_requests().is_empty());
+    }
+
+    #[test]
+    fn treats_an_unsatisfiable_range_as_complete() {
+        // Without a size, the complete partial file is only noticed from the 416.
+        let hub = Hub::serving(content_hash(CONTENT), None);
+        let fixture = Fixture::new("unsatisfiable", &hub);
+        fixture.write_partial(CONTENT);
+        fixture.download().unwrap();
+        assert_eq!(fixture.snapshot(), CONTENT);
+        assert_eq!(hub.storage_requests(), vec![Some(CONTENT.len() as u64)]);
+    }
+
+    #[test]
+    fn rejects_and_removes_a_corrupt_download() {
+        let hub = Hub::serving(content_hash(b"other weights"), Some(CONTENT.len()));
+        let fixture = Fixture::new("corrupt", &hub);
+        let result = fixture.download();
+        assert!(matches!(result, Err(Error::ChecksumMismatch { .. })));
+        let leftovers = std::fs::read_dir(fixture.blobs()).unwrap().count();
+        assert_eq!(leftovers, 0);
+    }
+
+    fn git_hash(content: &[u8]) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(format!("blob {}\0", content.len()));
+        hasher.update(content);
+        hex(&hasher.finalize())
+    }
+
+    #[test]
+    fn verifies_files_outside_lfs_by_git_hash() {
+        let hub = Hub::serving_outside_lfs(git_hash(CONTENT));
+        let fixture = Fixture::new("outside-lfs", &hub);
+        fixture.download().unwrap();
+        assert_eq!(fixture.snapshot(), CONTENT);
+    }
+
+    #[test]
+    fn rejects_a_corrupt_file_outside_lfs() {
+        let hub = Hub::serving_outside_lfs(git_hash(b"other tokenizer"));
+        let fixture = Fixture::new("corrupt-outside-lfs", &hub);
+        let result = fixture.download();
+        assert!(matches!(result, Err(Error::ChecksumMismatch { .. })));
+    }
+}
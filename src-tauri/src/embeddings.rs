@@ -0,0 +1,239 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+use candle::{Device, Tensor};
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+const TENSOR_NAME: &str = "image_embeds";
+
+/// Hex encoded SHA-256 of the image contents, used as the embedding cache key.
+pub fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Size-bounded LRU of vision encoder outputs keyed by image content hash.
+/// When a directory is set, entries are also written there as safetensors so
+/// they survive restarts. The directory is an LRU of its own, holding at most
+/// `disk_capacity` files.
+pub struct EmbeddingCache {
+    capacity: usize,
+    dir: Option<PathBuf>,
+    disk_capacity: usize,
+    entries: HashMap<String, Tensor>,
+    order: VecDeque<String>,
+    /// Keys of the files in `dir`, least recently used first.
+    disk_order: VecDeque<String>,
+}
+
+impl EmbeddingCache {
+    pub fn new(capacity: usize, dir: Option<PathBuf>, disk_capacity: usize) -> Self {
+        let mut cache = Self {
+            capacity,
+            dir,
+            disk_capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            disk_order: VecDeque::new(),
+        };
+        if let Some(dir) = &cache.dir {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                tracing::error!("Could not create embedding cache dir {:?}: {:?}", dir, e);
+            }
+            cache.disk_order = files_by_age(dir);
+            cache.evict_files();
+        }
+        cache
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{key}.safetensors")))
+    }
+
+    fn insert(&mut self, key: &str, embeds: Tensor) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.insert(key.to_string(), embeds);
+        touch(&mut self.order, key);
+        while self.order.len() > self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                tracing::debug!("Evicting image embeddings {}", evicted);
+                self.entries.remove(&evicted);
+            }
+        }
+    }
+
+    fn load_from_disk(&self, path: &Path, device: &Device) -> Option<Tensor> {
+        if !path.exists() {
+            return None;
+        }
+        match candle::safetensors::load(path, device) {
+            Ok(mut tensors) => tensors.remove(TENSOR_NAME),
+            Err(e) => {
+                tracing::error!("Could not read cached embeddings {:?}: {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Deletes the least recently used files until the directory is within
+    /// `disk_capacity`.
+    fn evict_files(&mut self) {
+        while self.disk_order.len() > self.disk_capacity {
+            let Some(evicted) = self.disk_order.pop_front() else {
+                break;
+            };
+            let Some(path) = self.path(&evicted) else {
+                break;
+            };
+            tracing::debug!("Deleting cached embeddings {:?}", path);
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::error!("Could not delete cached embeddings {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    pub fn get(&mut self, key: &str, device: &Device) -> Option<Tensor> {
+        if let Some(embeds) = self.entries.get(key).cloned() {
+            touch(&mut self.order, key);
+            if self.disk_order.iter().any(|k| k == key) {
+                touch(&mut self.disk_order, key);
+            }
+            return Some(embeds);
+        }
+        let embeds = self.load_from_disk(&self.path(key)?, device)?;
+        touch(&mut self.disk_order, key);
+        self.insert(key, embeds.clone());
+        Some(embeds)
+    }
+
+    /// Stores freshly computed embeddings, in memory and on disk.
+    pub fn put(&mut self, key: &str, embeds: Tensor) {
+        if let Some(path) = self.path(key) {
+            match embeds.save_safetensors(TENSOR_NAME, &path) {
+                Ok(()) => {
+                    touch(&mut self.disk_order, key);
+                    self.evict_files();
+                }
+                Err(e) => tracing::error!("Could not persist embeddings to {:?}: {:?}", path, e),
+            }
+        }
+        self.insert(key, embeds);
+    }
+
+    /// Drops every entry, in memory and on disk.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.entries.clear();
+        self.order.clear();
+        self.disk_order.clear();
+        if let Some(dir) = &self.dir {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "safetensors") {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Moves `key` to the most recently used end of `order`.
+fn touch(order: &mut VecDeque<String>, key: &str) {
+    if let Some(pos) = order.iter().position(|k| k == key) {
+        order.remove(pos);
+    }
+    order.push_back(key.to_string());
+}
+
+/// Keys of the embeddings persisted in `dir`, oldest first, so a restart picks
+/// up the disk LRU roughly where it was.
+fn files_by_age(dir: &Path) -> VecDeque<String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!("Could not list embedding cache dir {:?}: {:?}", dir, e);
+            return VecDeque::new();
+        }
+    };
+    let mut files: Vec<(std::time::SystemTime, String)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "safetensors" {
+                return None;
+            }
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((modified, path.file_stem()?.to_string_lossy().to_string()))
+        })
+        .collect();
+    files.sort();
+    files.into_iter().map(|(_, key)| key).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::DType;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "moondream-embeddings-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn files(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn evicts_files_beyond_disk_capacity() {
+        let dir = test_dir("evict");
+        let embeds = Tensor::zeros((1, 2), DType::F32, &Device::Cpu).unwrap();
+        let mut cache = EmbeddingCache::new(1, Some(dir.clone()), 2);
+        for key in ["a", "b", "c"] {
+            cache.put(key, embeds.clone());
+        }
+        assert_eq!(files(&dir), 2);
+        assert!(!dir.join("a.safetensors").exists());
+        // A file read back counts as used, so "c" goes before "b".
+        assert!(cache.get("b", &Device::Cpu).is_some());
+        cache.put("d", embeds);
+        assert!(dir.join("b.safetensors").exists());
+        assert!(!dir.join("c.safetensors").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_the_directory_on_open() {
+        let dir = test_dir("open");
+        let embeds = Tensor::zeros((1, 2), DType::F32, &Device::Cpu).unwrap();
+        let mut cache = EmbeddingCache::new(4, Some(dir.clone()), 4);
+        for key in ["a", "b", "c"] {
+            cache.put(key, embeds.clone());
+        }
+        drop(cache);
+        EmbeddingCache::new(4, Some(dir.clone()), 1);
+        assert_eq!(files(&dir), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
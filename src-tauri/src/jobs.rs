@@ -0,0 +1,264 @@
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tauri::ipc::Channel;
+
+use crate::{cancel::CancelToken, Error, GenerationEvent, RequestId};
+
+pub const DEFAULT_MAX_CONCURRENCY: usize = 1;
+pub const DEFAULT_MAX_QUEUED: usize = 64;
+
+/// Work done by a job once it is scheduled. It streams its output on the channel
+/// and should return `Error::Cancelled` soon after the token is cancelled.
+pub type JobFn = Box<dyn FnOnce(&Channel, &CancelToken) -> Result<(), Error> + Send>;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Generate,
+    Ask,
+    Batch,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Zero based position in the queue.
+    Queued {
+        position: usize,
+    },
+    Running,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub id: RequestId,
+    pub kind: JobKind,
+    pub prompt: String,
+    #[serde(flatten)]
+    pub status: JobStatus,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct SchedulerConfig {
+    pub max_concurrency: usize,
+    pub max_queued: usize,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            max_queued: DEFAULT_MAX_QUEUED,
+        }
+    }
+}
+
+struct QueuedJob {
+    id: RequestId,
+    kind: JobKind,
+    prompt: String,
+    on_event: Channel,
+    run: JobFn,
+}
+
+struct RunningJob {
+    kind: JobKind,
+    prompt: String,
+    cancel: CancelToken,
+}
+
+struct Inner {
+    config: SchedulerConfig,
+    next_id: RequestId,
+    queue: VecDeque<QueuedJob>,
+    running: HashMap<RequestId, RunningJob>,
+}
+
+/// Bounded FIFO of generation jobs, running at most `max_concurrency` of them at
+/// a time on blocking threads.
+pub struct Scheduler {
+    inner: Mutex<Inner>,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(Inner {
+                config,
+                next_id: 1,
+                queue: VecDeque::new(),
+                running: HashMap::new(),
+            }),
+        })
+    }
+
+    pub fn config(&self) -> SchedulerConfig {
+        self.inner.lock().unwrap().config
+    }
+
+    pub fn configure(self: &Arc<Self>, config: SchedulerConfig) -> Result<(), Error> {
+        if config.max_concurrency == 0 {
+            return Err(Error::InputError(
+                "Max concurrency must be positive".to_string(),
+            ));
+        }
+        self.inner.lock().unwrap().config = config;
+        self.start_ready();
+        Ok(())
+    }
+
+    /// Queues a job and returns its id, starting it right away if a slot is free.
+    pub fn submit(
+        self: &Arc<Self>,
+        kind: JobKind,
+        prompt: String,
+        on_event: Channel,
+        run: JobFn,
+    ) -> Result<RequestId, Error> {
+        self.submit_with_id(kind, prompt, on_event, |_| run)
+    }
+
+    /// Like `submit`, for jobs that need their id. `make_run` is called once the
+    /// id is reserved and before the job can start, so whatever it registers
+    /// under the id is there by the time the job runs.
+    pub fn submit_with_id<F>(
+        self: &Arc<Self>,
+        kind: JobKind,
+        prompt: String,
+        on_event: Channel,
+        make_run: F,
+    ) -> Result<RequestId, Error>
+    where
+        F: FnOnce(RequestId) -> JobFn,
+    {
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.queue.len() >= inner.config.max_queued {
+                return Err(Error::QueueFull(inner.config.max_queued));
+            }
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.queue.push_back(QueuedJob {
+                id,
+                kind,
+                prompt,
+                on_event,
+                run: make_run(id),
+            });
+            id
+        };
+        tracing::debug!("Job {} submitted", id);
+        self.start_ready();
+        Ok(id)
+    }
+
+    /// Cancels a queued job, or signals a running one to stop.
+    pub fn cancel(&self, id: RequestId) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(pos) = inner.queue.iter().position(|job| job.id == id) {
+            let job = inner.queue.remove(pos).expect("position is in range");
+            Self::send(&job.on_event, GenerationEvent::Cancelled);
+            Self::notify_positions(&inner.queue);
+            return Ok(());
+        }
+        match inner.running.get(&id) {
+            Some(job) => {
+                job.cancel.cancel();
+                Ok(())
+            }
+            None => Err(Error::JobNotFound(id)),
+        }
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let inner = self.inner.lock().unwrap();
+        let running = inner.running.iter().map(|(id, job)| JobInfo {
+            id: *id,
+            kind: job.kind,
+            prompt: job.prompt.clone(),
+            status: JobStatus::Running,
+        });
+        let queued = inner
+            .queue
+            .iter()
+            .enumerate()
+            .map(|(position, job)| JobInfo {
+                id: job.id,
+                kind: job.kind,
+                prompt: job.prompt.clone(),
+                status: JobStatus::Queued { position },
+            });
+        let mut jobs: Vec<JobInfo> = running.chain(queued).collect();
+        jobs.sort_by_key(|job| job.id);
+        jobs
+    }
+
+    fn start_ready(self: &Arc<Self>) {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.running.len() < inner.config.max_concurrency {
+            let Some(job) = inner.queue.pop_front() else {
+                break;
+            };
+            let cancel = CancelToken::default();
+            inner.running.insert(
+                job.id,
+                RunningJob {
+                    kind: job.kind,
+                    prompt: job.prompt,
+                    cancel: cancel.clone(),
+                },
+            );
+            let scheduler = self.clone();
+            let id = job.id;
+            tracing::debug!("Job {} started", id);
+            Self::send(&job.on_event, GenerationEvent::Started);
+            tauri::async_runtime::spawn_blocking(move || {
+                let on_event = job.on_event;
+                let run = job.run;
+                let result = panic::catch_unwind(AssertUnwindSafe(|| run(&on_event, &cancel)))
+                    .unwrap_or_else(|panic| {
+                        let message = panic
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        Err(Error::Panicked(message))
+                    });
+                match result {
+                    Ok(()) => {}
+                    Err(Error::Cancelled) => {
+                        tracing::debug!("Job {} cancelled", id);
+                        Self::send(&on_event, GenerationEvent::Cancelled);
+                    }
+                    Err(e) => {
+                        tracing::error!("Job {} failed: {:?}", id, e);
+                        Self::send(&on_event, GenerationEvent::from(&e));
+                    }
+                }
+                scheduler.finish(id);
+            });
+        }
+        Self::notify_positions(&inner.queue);
+    }
+
+    fn finish(self: &Arc<Self>, id: RequestId) {
+        tracing::debug!("Job {} finished", id);
+        self.inner.lock().unwrap().running.remove(&id);
+        self.start_ready();
+    }
+
+    fn notify_positions(queue: &VecDeque<QueuedJob>) {
+        for (position, job) in queue.iter().enumerate() {
+            Self::send(&job.on_event, GenerationEvent::Queued { position });
+        }
+    }
+
+    fn send(on_event: &Channel, event: GenerationEvent) {
+        if let Err(e) = on_event.send(event) {
+            tracing::error!("Could not send job event: {:?}", e);
+        }
+    }
+}
@@ -1,24 +1,59 @@
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
+use batch::{BatchControl, BatchRequest};
+use cancel::CancelToken;
 use candle::Device;
+use download::{DownloadProgress, Downloader};
+use embeddings::EmbeddingCache;
+use jobs::{JobInfo, JobKind, Scheduler, SchedulerConfig};
+use library::{ImageLibrary, LibraryImage, PendingImport};
+use moondream::{
+    ComputeDtype, FinishReason, ModelDescriptor, ModelSource, ModelStatus, Moondream, Pipeline,
+    SamplingConfig, Session, StoppingConfig, Turn,
+};
+use preprocess::{FrameSelection, PreprocessSettings, Region};
 use serde::{Deserialize, Serialize};
-use tauri::Manager;
+use settings::Settings;
+use tauri::{ipc::Channel, Manager};
 use tauri_plugin_dialog::DialogExt;
 use tauri_plugin_log::{Target, TargetKind};
 use tracing::{debug, error, info};
+use utils::{CpuFeatures, DeviceSetting, ImageInput};
 
 pub mod base64img;
+pub mod batch;
+pub mod cancel;
+pub mod download;
+pub mod embeddings;
+pub mod jobs;
+pub mod library;
 pub mod moondream;
+pub mod preprocess;
+pub mod settings;
 pub mod utils;
 
-const ASSETS_DIR: &str = "/Users/santiagomedina/tauri-moondream/assets";
 const TARGET: &str = env!("TARGET");
+const EMBEDDING_CACHE_CAPACITY: usize = 32;
+/// Embeddings kept on disk across restarts, a few MB each.
+const EMBEDDING_DISK_CAPACITY: usize = 64;
+/// How often a job waiting for the model lock checks whether it was cancelled.
+const MODEL_LOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error("{path}: {source}")]
+    File {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
     #[error(transparent)]
     Json(#[from] serde_json::Error),
 
@@ -26,25 +61,171 @@ pub enum Error {
     Tauri(#[from] tauri::Error),
 
     #[error(transparent)]
-    Lock(#[from] tokio::sync::TryLockError),
+    Join(#[from] tokio::task::JoinError),
 
     #[error(transparent)]
-    Api(#[from] hf_hub::api::sync::ApiError),
+    Candle(#[from] candle::Error),
 
     #[error(transparent)]
-    Candle(#[from] candle::Error),
+    Image(#[from] image::ImageError),
 
     #[error(transparent)]
     Tokenizer(#[from] Box<dyn std::error::Error + Send + Sync>),
 
-    #[error("Model {0} was not found")]
-    ModelNotFound(String),
+    #[error("Model {model} was not found, missing {}", .missing.join(", "))]
+    ModelNotFound { model: String, missing: Vec<String> },
 
     #[error("Special token {0} was not found")]
     SpecialTokenNotFound(String),
 
     #[error("Input error {0}")]
     InputError(String),
+
+    #[error("The job queue is full ({0} jobs waiting)")]
+    QueueFull(usize),
+
+    #[error("Job {0} was not found")]
+    JobNotFound(RequestId),
+
+    #[error("Image {0} is not in the library")]
+    ImageNotFound(String),
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("Generation task panicked: {0}")]
+    Panicked(String),
+
+    #[error("Download of {url} failed: {message}")]
+    Download { url: String, message: String },
+
+    #[error("Checksum mismatch for {file}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Error {
+    /// Stable identifier of the variant, for the frontend to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::File { .. } => "file",
+            Error::Json(_) => "json",
+            Error::Tauri(_) => "tauri",
+            Error::Join(_) => "join",
+            Error::Candle(_) => "candle",
+            Error::Image(_) => "image",
+            Error::Tokenizer(_) => "tokenizer",
+            Error::ModelNotFound { .. } => "model_not_found",
+            Error::SpecialTokenNotFound(_) => "special_token_not_found",
+            Error::InputError(_) => "input_error",
+            Error::QueueFull(_) => "queue_full",
+            Error::JobNotFound(_) => "job_not_found",
+            Error::ImageNotFound(_) => "image_not_found",
+            Error::Cancelled => "cancelled",
+            Error::Panicked(_) => "panicked",
+            Error::Download { .. } => "download",
+            Error::ChecksumMismatch { .. } => "checksum_mismatch",
+        }
+    }
+
+    /// Whether trying the same request again later may succeed.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::QueueFull(_)
+                | Error::Cancelled
+                | Error::Download { .. }
+                | Error::ChecksumMismatch { .. }
+        )
+    }
+
+    pub fn details(&self) -> Option<ErrorDetails> {
+        let details = match self {
+            Error::File { path, .. } => ErrorDetails {
+                path: Some(path.clone()),
+                ..Default::default()
+            },
+            Error::ModelNotFound { model, missing } => ErrorDetails {
+                model_id: Some(model.clone()),
+                files: Some(missing.clone()),
+                ..Default::default()
+            },
+            Error::SpecialTokenNotFound(token) => ErrorDetails {
+                token: Some(token.clone()),
+                ..Default::default()
+            },
+            Error::Download { url, .. } => ErrorDetails {
+                url: Some(url.clone()),
+                ..Default::default()
+            },
+            Error::ChecksumMismatch { file, .. } => ErrorDetails {
+                path: Some(file.clone()),
+                ..Default::default()
+            },
+            Error::JobNotFound(id) => ErrorDetails {
+                request_id: Some(*id),
+                ..Default::default()
+            },
+            Error::ImageNotFound(id) => ErrorDetails {
+                image_id: Some(id.clone()),
+                ..Default::default()
+            },
+            _ => return None,
+        };
+        Some(details)
+    }
+
+    /// Wraps an io error with the path it happened on, for use with `map_err`.
+    pub fn file(path: impl AsRef<Path>) -> impl FnOnce(std::io::Error) -> Error {
+        let path = path.as_ref().to_string_lossy().to_string();
+        move |source| Error::File { path, source }
+    }
+}
+
+/// Context attached to an [`Error`] when it is known.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ErrorDetails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    /// Files that were expected but not found.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<RequestId>,
+}
+
+/// How an [`Error`] reaches the frontend, both as a command error and on a
+/// request's channel.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ErrorPayload {
+    code: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<ErrorDetails>,
+    retryable: bool,
+}
+
+impl From<&Error> for ErrorPayload {
+    fn from(e: &Error) -> Self {
+        ErrorPayload {
+            code: e.code().to_string(),
+            message: e.to_string(),
+            details: e.details(),
+            retryable: e.retryable(),
+        }
+    }
 }
 
 impl Serialize for Error {
@@ -52,46 +233,239 @@ impl Serialize for Error {
     where
         S: serde::ser::Serializer,
     {
-        serializer.serialize_str(self.to_string().as_ref())
+        ErrorPayload::from(self).serialize(serializer)
     }
 }
 
+pub type SessionId = u64;
+pub type RequestId = u64;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Token {
     id: usize,
     text: String,
     special: bool,
 }
+
+impl Token {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_special(&self) -> bool {
+        self.special
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Generation {
     token: Token,
     generated_text: Option<String>,
-    details: Option<bool>,
+    details: Option<GenerationDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    part: Option<ImagePart>,
+}
+
+impl Generation {
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+
+    /// Set on the last generation of an answer.
+    pub fn details(&self) -> Option<&GenerationDetails> {
+        self.details.as_ref()
+    }
+}
+
+/// Which frame or tile a [`Generation`] answers about, when a request asks the
+/// same question about several.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImagePart {
+    frame: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tile: Option<Region>,
+    /// Position of this answer among the answers of the request.
+    index: usize,
+    count: usize,
+}
+
+impl ImagePart {
+    pub fn new(frame: usize, tile: Option<Region>, index: usize, count: usize) -> Self {
+        Self {
+            frame,
+            tile,
+            index,
+            count,
+        }
+    }
+}
+
+/// Sent with the last [`Generation`] of a request.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GenerationDetails {
+    finish_reason: FinishReason,
+    prompt_tokens: usize,
+    generated_tokens: usize,
+    /// Includes the vision encoder, `None` if no token was sampled.
+    time_to_first_token_ms: Option<f64>,
+    vision_encode_ms: f64,
+    tokens_per_second: f64,
+}
+
+/// Message sent on the channel of a generation request.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GenerationEvent {
+    /// Waiting for a free slot, sent whenever the position changes.
+    Queued {
+        position: usize,
+    },
+    Started,
+    Generation(Generation),
+    /// Answers of a tiled request asked to aggregate them, sent once every tile
+    /// has been answered instead of streaming each answer.
+    Aggregated {
+        generated_text: String,
+        answers: Vec<PartAnswer>,
+    },
+    /// Cancelled while queued, loading the model or encoding the image.
+    Cancelled,
+    /// The job failed, this is the last event of the request.
+    Error(ErrorPayload),
+}
+
+/// The answer about a single frame or tile of an aggregated request.
+#[derive(Debug, Clone, Serialize)]
+pub struct PartAnswer {
+    part: ImagePart,
+    answer: String,
+}
+
+impl From<&Error> for GenerationEvent {
+    fn from(e: &Error) -> Self {
+        GenerationEvent::Error(e.into())
+    }
 }
 
 struct State {
     cache: hf_hub::Cache,
-    device: Device,
-    tx: tokio::sync::Mutex<Option<tokio::sync::oneshot::Sender<()>>>,
+    device: std::sync::Mutex<Device>,
+    settings: std::sync::Mutex<Settings>,
+    settings_path: Option<PathBuf>,
+    model: tokio::sync::Mutex<Option<Arc<Moondream>>>,
+    model_status: std::sync::Mutex<ModelStatus>,
+    embeddings: std::sync::Mutex<EmbeddingCache>,
+    library: std::sync::Mutex<ImageLibrary>,
+    sessions: std::sync::Mutex<HashMap<SessionId, OpenSession>>,
+    next_session_id: AtomicU64,
+    jobs: Arc<Scheduler>,
+    /// Cancels the model download in progress, if any.
+    download: std::sync::Mutex<Option<CancelToken>>,
+    batches: std::sync::Mutex<HashMap<RequestId, Arc<BatchControl>>>,
 }
 
-#[tauri::command]
-fn copy_image(src: String) -> Result<String, Error> {
-    let src = Path::new(&src);
-    debug!("copying image {:?} ", src);
-    if let Some(filename) = src.file_name() {
-        let dst = Path::new(ASSETS_DIR).join(filename);
-        debug!("to {:?}", dst);
-        if !dst.exists() {
-            std::fs::copy(src, &dst)?;
-        }
-        return Ok(dst.to_string_lossy().to_string());
-    } else {
-        return Err(Error::Io(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "Could not find filename",
-        )));
+fn set_model_status(app: &tauri::AppHandle, status: ModelStatus) {
+    debug!("Model status: {:?}", status);
+    let state = app.state::<State>();
+    *state.model_status.lock().unwrap() = status.clone();
+    if let Err(e) = app.emit("model-status", status) {
+        error!("Could not emit model status: {:?}", e);
+    }
+}
+
+/// Returns the resident model, loading it first if this is the first use.
+/// Must be called from a blocking context while holding the `State::model` lock.
+fn ensure_model(
+    app: &tauri::AppHandle,
+    model: &mut Option<Arc<Moondream>>,
+    cancel: &CancelToken,
+) -> Result<Arc<Moondream>, Error> {
+    if model.is_none() {
+        let state = app.state::<State>();
+        let device = state.device.lock().unwrap().clone();
+        let (descriptor, dtype, preprocess) = {
+            let settings = state.settings.lock().unwrap();
+            let dtype = settings
+                .dtype
+                .unwrap_or_else(|| ComputeDtype::for_device(&device));
+            (settings.model.clone(), dtype, settings.preprocess)
+        };
+        let resource_dir = app.path().resource_dir().ok();
+        let descriptor = descriptor.resolve_bundled(resource_dir.as_deref());
+        let moondream = Moondream::load(
+            &descriptor,
+            &device,
+            dtype.dtype(),
+            preprocess,
+            &state.cache,
+            cancel,
+            |status| set_model_status(app, status),
+        )
+        .map_err(|e| {
+            let status = match &e {
+                Error::Cancelled => ModelStatus::NotLoaded,
+                Error::ModelNotFound { missing, .. } if descriptor.source == ModelSource::Hub => {
+                    ModelStatus::NotDownloaded {
+                        missing: missing.clone(),
+                    }
+                }
+                _ => ModelStatus::Failed {
+                    error: e.to_string(),
+                },
+            };
+            set_model_status(app, status);
+            e
+        })?;
+        *model = Some(Arc::new(moondream));
+        set_model_status(app, ModelStatus::Ready);
     }
+    Ok(model.clone().expect("model was just loaded"))
+}
+
+/// Locks the model only for as long as it takes to load it.
+/// Must be called from a blocking context. Waiting for the lock, held by
+/// another job loading the model, polls `cancel`.
+fn resident_model(app: &tauri::AppHandle, cancel: &CancelToken) -> Result<Arc<Moondream>, Error> {
+    let state = app.state::<State>();
+    let mut model = loop {
+        cancel.check()?;
+        match state.model.try_lock() {
+            Ok(model) => break model,
+            Err(_) => std::thread::sleep(MODEL_LOCK_POLL_INTERVAL),
+        }
+    };
+    ensure_model(app, &mut model, cancel)
+}
+
+/// Copies an image into the library, returning the entry to generate from.
+#[tauri::command]
+async fn import_image(app: tauri::AppHandle, src: String) -> Result<LibraryImage, Error> {
+    debug!("Importing image {:?}", src);
+    tokio::task::spawn_blocking(move || {
+        let state = app.state::<State>();
+        let pending = PendingImport::read(Path::new(&src))?;
+        state.library.lock().unwrap().import(pending)
+    })
+    .await?
+}
+
+#[tauri::command]
+fn list_images(state: tauri::State<'_, State>) -> Vec<LibraryImage> {
+    state.library.lock().unwrap().list().to_vec()
+}
+
+#[tauri::command]
+fn rename_image(
+    state: tauri::State<'_, State>,
+    id: String,
+    name: String,
+) -> Result<LibraryImage, Error> {
+    state.library.lock().unwrap().rename(&id, name)
+}
+
+#[tauri::command]
+fn delete_image(state: tauri::State<'_, State>, id: String) -> Result<(), Error> {
+    state.library.lock().unwrap().delete(&id)
 }
 
 #[tauri::command]
@@ -117,59 +491,565 @@ async fn open_image(app: tauri::AppHandle) -> Result<String, Error> {
 }
 
 #[tauri::command]
-async fn stop(state: tauri::State<'_, State>) -> Result<(), Error> {
-    info!("STOP called");
-    let mut tx = state.tx.try_lock()?;
-    let tmptx = (*tx).take();
-    if let Some(tx) = tmptx {
-        if let Err(_) = tx.send(()) {
-            error!("Could not send stop signal");
+async fn load_model(app: tauri::AppHandle) -> Result<ModelStatus, Error> {
+    let handle = app.clone();
+    tokio::task::spawn_blocking(move || {
+        resident_model(&handle, &CancelToken::default()).map(|_| ())
+    })
+    .await??;
+    Ok(app.state::<State>().model_status.lock().unwrap().clone())
+}
+
+#[tauri::command]
+fn get_model(state: tauri::State<'_, State>) -> ModelDescriptor {
+    state.settings.lock().unwrap().model.clone()
+}
+
+/// Switches to another model. The current one is dropped and the new one is
+/// loaded on next use; open sessions keep the model they were created with in
+/// memory until they are closed.
+#[tauri::command]
+async fn set_model(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, State>,
+    descriptor: ModelDescriptor,
+) -> Result<(), Error> {
+    info!("Switching model to {:?}", descriptor);
+    let mut model = state.model.lock().await;
+    {
+        let mut settings = state.settings.lock().unwrap();
+        if settings.model == descriptor {
+            return Ok(());
+        }
+        settings.model = descriptor;
+        if let Some(path) = &state.settings_path {
+            settings.save(path)?;
         }
     }
+    // Cached embeddings are keyed by model, so they stay valid for switching back.
+    *model = None;
+    set_model_status(&app, ModelStatus::NotLoaded);
     Ok(())
 }
 
 #[tauri::command]
-async fn generate(
+fn get_dtype(state: tauri::State<'_, State>) -> Option<ComputeDtype> {
+    state.settings.lock().unwrap().dtype
+}
+
+/// Overrides the compute dtype, `None` going back to the device default. The
+/// model is reloaded in the new dtype on next use.
+#[tauri::command]
+async fn set_dtype(
     app: tauri::AppHandle,
     state: tauri::State<'_, State>,
-    prompt: String,
-    image: String,
+    dtype: Option<ComputeDtype>,
 ) -> Result<(), Error> {
-    debug!("Generating for {prompt} and {image}");
-    let (newtx, mut rx) = tokio::sync::oneshot::channel();
-    let cache = state.cache.clone();
-    let device = state.device.clone();
+    info!("Switching dtype to {:?}", dtype);
+    let mut model = state.model.lock().await;
+    {
+        let mut settings = state.settings.lock().unwrap();
+        if settings.dtype == dtype {
+            return Ok(());
+        }
+        settings.dtype = dtype;
+        if let Some(path) = &state.settings_path {
+            settings.save(path)?;
+        }
+    }
+    *model = None;
+    set_model_status(&app, ModelStatus::NotLoaded);
+    Ok(())
+}
+
+#[tauri::command]
+fn get_preprocess(state: tauri::State<'_, State>) -> PreprocessSettings {
+    state.settings.lock().unwrap().preprocess
+}
+
+/// Changes how images are resized before encoding. Takes effect on the next
+/// image, without reloading the model.
+#[tauri::command]
+async fn set_preprocess(
+    state: tauri::State<'_, State>,
+    preprocess: PreprocessSettings,
+) -> Result<(), Error> {
+    info!("Switching preprocessing to {:?}", preprocess);
+    let model = state.model.lock().await;
+    let mut settings = state.settings.lock().unwrap();
+    settings.preprocess = preprocess;
+    if let Some(path) = &state.settings_path {
+        settings.save(path)?;
+    }
+    if let Some(model) = model.as_ref() {
+        model.set_preprocess(preprocess);
+    }
+    Ok(())
+}
+
+/// Devices the model can run on and the one it uses now.
+#[derive(Debug, Clone, Serialize)]
+struct DeviceList {
+    available: Vec<DeviceSetting>,
+    selected: DeviceSetting,
+    active: DeviceSetting,
+    cpu: CpuFeatures,
+}
+
+#[tauri::command]
+fn get_devices(state: tauri::State<'_, State>) -> DeviceList {
+    DeviceList {
+        available: utils::available_devices(),
+        selected: state.settings.lock().unwrap().device,
+        active: DeviceSetting::from(&*state.device.lock().unwrap()),
+        cpu: CpuFeatures::detect(),
+    }
+}
+
+/// Moves to another device. A loaded model is dropped and loaded again on the
+/// new device right away.
+#[tauri::command]
+async fn set_device(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, State>,
+    device: DeviceSetting,
+) -> Result<(), Error> {
+    info!("Switching device to {}", device);
+    let mut model = state.model.lock().await;
+    let new_device = utils::device(device)?;
+    {
+        let mut settings = state.settings.lock().unwrap();
+        settings.device = device;
+        if let Some(path) = &state.settings_path {
+            settings.save(path)?;
+        }
+    }
+    *state.device.lock().unwrap() = new_device;
+    if model.take().is_some() {
+        set_model_status(&app, ModelStatus::NotLoaded);
+        drop(model);
+        let handle = app.clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            if let Err(e) = resident_model(&handle, &CancelToken::default()) {
+                error!("Could not reload the model: {:?}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn downloader(state: &State) -> Downloader {
+    let endpoint = state.settings.lock().unwrap().hub_endpoint.clone();
+    Downloader::new(state.cache.clone(), endpoint)
+}
+
+#[tauri::command]
+fn model_status(state: tauri::State<'_, State>) -> ModelStatus {
+    let status = state.model_status.lock().unwrap().clone();
+    let descriptor = state.settings.lock().unwrap().model.clone();
+    match status {
+        ModelStatus::NotLoaded if descriptor.source == ModelSource::Hub => {
+            let missing = downloader(&state).missing_files(&descriptor);
+            if missing.is_empty() {
+                ModelStatus::NotLoaded
+            } else {
+                ModelStatus::NotDownloaded { missing }
+            }
+        }
+        status => status,
+    }
+}
+
+/// Downloads the files of the configured hub model into the cache, emitting
+/// `download-progress` events. Files already cached are skipped.
+#[tauri::command]
+async fn download_model(app: tauri::AppHandle) -> Result<(), Error> {
+    let handle = app.clone();
     tokio::task::spawn_blocking(move || {
-        let mut moondream = match moondream::build_pipeline(prompt, image, &device, &cache) {
+        let state = handle.state::<State>();
+        let descriptor = state.settings.lock().unwrap().model.clone();
+        if descriptor.source != ModelSource::Hub {
+            return Err(Error::InputError(
+                "Only hub models can be downloaded".to_string(),
+            ));
+        }
+        let cancel = CancelToken::default();
+        {
+            let mut download = state.download.lock().unwrap();
+            if download.is_some() {
+                return Err(Error::InputError(
+                    "A download is already running".to_string(),
+                ));
+            }
+            *download = Some(cancel.clone());
+        }
+        let result = downloader(&state).download(&descriptor, &cancel, |progress| {
+            let status = ModelStatus::Downloading {
+                file: progress.file.clone(),
+                progress: progress
+                    .total
+                    .map(|total| progress.downloaded as f32 / total.max(1) as f32)
+                    .unwrap_or(0.0),
+            };
+            set_model_status(&handle, status);
+            emit_download_progress(&handle, progress);
+        });
+        *state.download.lock().unwrap() = None;
+        let status = match &result {
+            Ok(()) | Err(Error::Cancelled) => ModelStatus::NotLoaded,
+            Err(e) => ModelStatus::Failed {
+                error: e.to_string(),
+            },
+        };
+        set_model_status(&handle, status);
+        result
+    })
+    .await?
+}
+
+fn emit_download_progress(app: &tauri::AppHandle, progress: DownloadProgress) {
+    if let Err(e) = app.emit("download-progress", progress) {
+        error!("Could not emit download progress: {:?}", e);
+    }
+}
+
+#[tauri::command]
+fn cancel_download(state: tauri::State<'_, State>) -> Result<(), Error> {
+    match state.download.lock().unwrap().as_ref() {
+        Some(cancel) => {
+            cancel.cancel();
+            Ok(())
+        }
+        None => Err(Error::InputError("No download is running".to_string())),
+    }
+}
+
+#[tauri::command]
+fn clear_embedding_cache(state: tauri::State<'_, State>) -> Result<(), Error> {
+    state.embeddings.lock().unwrap().clear()
+}
+
+#[tauri::command]
+fn stop(state: tauri::State<'_, State>, request_id: RequestId) -> Result<(), Error> {
+    info!("STOP called for request {request_id}");
+    state.jobs.cancel(request_id)?;
+    // A batch cancelled while queued never runs to unregister itself.
+    state.batches.lock().unwrap().remove(&request_id);
+    Ok(())
+}
+
+#[tauri::command]
+fn list_jobs(state: tauri::State<'_, State>) -> Vec<JobInfo> {
+    state.jobs.list()
+}
+
+#[tauri::command]
+fn configure_jobs(
+    state: tauri::State<'_, State>,
+    config: Option<SchedulerConfig>,
+) -> Result<SchedulerConfig, Error> {
+    if let Some(config) = config {
+        state.jobs.configure(config)?;
+    }
+    Ok(state.jobs.config())
+}
+
+fn emit_generations(on_event: &Channel, pipeline: &mut Pipeline) -> Result<(), Error> {
+    loop {
+        let next = pipeline.iter().next();
+        let generation = match next {
+            None => break,
+            Some(Ok(generation)) => generation,
+            Some(Err(e)) => {
+                error!("Generation failed: {:?}", e);
+                let generation = pipeline.interrupted(FinishReason::Error)?;
+                on_event.send(GenerationEvent::Generation(generation))?;
+                return Err(e);
+            }
+        };
+        debug!("Emitting generation: {:?}", generation);
+        on_event.send(GenerationEvent::Generation(generation))?;
+    }
+    Ok(())
+}
+
+fn run_generate(
+    app: &tauri::AppHandle,
+    prompt: String,
+    image: ImageInput,
+    sampling: SamplingConfig,
+    stopping: StoppingConfig,
+    on_event: &Channel,
+    cancel: &CancelToken,
+) -> Result<(), Error> {
+    let state = app.state::<State>();
+    let loaded = resident_model(app, cancel)?;
+    let images = moondream::encode_image(&image, &loaded, &state.embeddings, cancel);
+    let images = images.map_err(|e| {
+        error!("Could not encode image: {:?}", e);
+        e
+    })?;
+    let count = images.len();
+    // A single frame keeps the plain answer format, unless it was picked explicitly.
+    let tag_parts =
+        count > 1 || image.frames != FrameSelection::default() || image.tiling.is_some();
+    let aggregate = image.tiling.is_some_and(|tiling| tiling.aggregate);
+    let mut answers = vec![];
+    for (index, encoded) in images.into_iter().enumerate() {
+        // The part that was cancelled already sent its final generation.
+        cancel.check()?;
+        let part = ImagePart::new(encoded.frame(), encoded.tile(), index, count);
+        let mut text_model = loaded.text_model();
+        let pipeline = moondream::build_pipeline(
+            &prompt,
+            encoded,
+            &loaded,
+            &mut text_model,
+            &sampling,
+            &stopping,
+            cancel,
+        );
+        let mut moondream = match pipeline {
             Ok(moondream) => moondream,
             Err(e) => {
                 error!("Could not build pipeline: {:?}", e);
                 return Err(e);
             }
         };
-        info!("Pipeline created");
-        for generation in moondream.iter() {
-            let generation = generation?;
-            debug!("Emitting generation: {:?}", generation);
-            app.emit("text-generation", generation)?;
-            if let Ok(_) = rx.try_recv() {
-                break;
+        info!("Pipeline created for {:?}", part);
+        if aggregate {
+            for generation in moondream.iter() {
+                generation?;
             }
+            cancel.check()?;
+            answers.push(PartAnswer {
+                part,
+                answer: moondream.generated_text()?,
+            });
+            continue;
         }
-        Ok::<(), Error>(())
-    });
-    let mut tx = state.tx.try_lock()?;
-    let tmptx = (*tx).take();
-    if let Some(tx) = tmptx {
-        if let Err(_) = tx.send(()) {
-            error!("Could not send to tx");
+        if tag_parts {
+            moondream.set_part(part);
         }
+        emit_generations(on_event, &mut moondream)?;
     }
-    *tx = Some(newtx);
+    if aggregate {
+        on_event.send(GenerationEvent::Aggregated {
+            generated_text: aggregate_answers(&answers),
+            answers,
+        })?;
+    }
+    Ok(())
+}
+
+/// Joins the distinct non empty answers of the tiles, in tile order.
+fn aggregate_answers(answers: &[PartAnswer]) -> String {
+    let mut seen = HashSet::new();
+    answers
+        .iter()
+        .map(|answer| answer.answer.trim())
+        .filter(|answer| !answer.is_empty() && seen.insert(*answer))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[tauri::command]
+fn generate(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, State>,
+    prompt: String,
+    image: ImageInput,
+    sampling: Option<SamplingConfig>,
+    stopping: Option<StoppingConfig>,
+    on_event: Channel,
+) -> Result<RequestId, Error> {
+    debug!("Generating for {prompt} and {image} with {sampling:?}");
+    let sampling = sampling.unwrap_or_default();
+    let stopping = stopping.unwrap_or_default();
+    let job_prompt = prompt.clone();
+    state.jobs.submit(
+        JobKind::Generate,
+        job_prompt,
+        on_event,
+        Box::new(move |on_event, cancel| {
+            run_generate(&app, prompt, image, sampling, stopping, on_event, cancel)
+        }),
+    )
+}
+
+/// Asks the prompts about every image of the request, streaming results and
+/// progress on `on_event`. Stopped with `stop` like any other job.
+#[tauri::command]
+async fn batch_generate(
+    app: tauri::AppHandle,
+    request: BatchRequest,
+    on_event: Channel,
+) -> Result<RequestId, Error> {
+    // Globbing a large tree takes a while, keep it off the main thread.
+    let (request, images) = tokio::task::spawn_blocking(move || {
+        let images = request.images()?;
+        Ok::<_, Error>((request, images))
+    })
+    .await??;
+    info!(
+        "Batch of {} images and {} prompts",
+        images.len(),
+        request.prompts.len()
+    );
+    let state = app.state::<State>();
+    let handle = app.clone();
+    state.jobs.submit_with_id(
+        JobKind::Batch,
+        request.prompts.join(" | "),
+        on_event,
+        |id| {
+            let control = Arc::new(BatchControl::default());
+            state.batches.lock().unwrap().insert(id, control.clone());
+            Box::new(move |on_event, cancel| {
+                let state = handle.state::<State>();
+                let result = resident_model(&handle, cancel).and_then(|loaded| {
+                    batch::run(
+                        &request,
+                        &images,
+                        &loaded,
+                        &state.embeddings,
+                        &control,
+                        cancel,
+                        |event| Ok(on_event.send(event)?),
+                    )
+                });
+                state.batches.lock().unwrap().remove(&id);
+                result
+            })
+        },
+    )
+}
+
+fn get_batch(state: &State, id: RequestId) -> Result<Arc<BatchControl>, Error> {
+    state
+        .batches
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or(Error::JobNotFound(id))
+}
+
+/// Pauses a batch once the item in progress is done.
+#[tauri::command]
+fn pause_batch(state: tauri::State<'_, State>, request_id: RequestId) -> Result<(), Error> {
+    get_batch(&state, request_id)?.pause();
+    Ok(())
+}
+
+#[tauri::command]
+fn resume_batch(state: tauri::State<'_, State>, request_id: RequestId) -> Result<(), Error> {
+    get_batch(&state, request_id)?.resume();
+    Ok(())
+}
+
+/// A session and its turns. The session is locked while it answers, the turns
+/// only while they are updated.
+#[derive(Clone)]
+struct OpenSession {
+    session: Arc<std::sync::Mutex<Session>>,
+    turns: Arc<std::sync::Mutex<Vec<Turn>>>,
+}
+
+fn get_session(state: &State, id: SessionId) -> Result<OpenSession, Error> {
+    state
+        .sessions
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| Error::InputError(format!("Session {id} does not exist")))
+}
+
+#[tauri::command]
+async fn create_session(app: tauri::AppHandle, image: ImageInput) -> Result<SessionId, Error> {
+    debug!("Creating session for {image}");
+    let handle = app.clone();
+    let session = tokio::task::spawn_blocking(move || {
+        let cancel = CancelToken::default();
+        let loaded = resident_model(&handle, &cancel)?;
+        let state = handle.state::<State>();
+        Session::new(&image, loaded, &state.embeddings, &cancel)
+    })
+    .await??;
+    let state = app.state::<State>();
+    let id = state.next_session_id.fetch_add(1, Ordering::Relaxed);
+    let session = OpenSession {
+        turns: session.turns(),
+        session: Arc::new(std::sync::Mutex::new(session)),
+    };
+    state.sessions.lock().unwrap().insert(id, session);
+    info!("Session {id} created");
+    Ok(id)
+}
+
+fn run_ask(
+    session: Arc<std::sync::Mutex<Session>>,
+    prompt: String,
+    sampling: SamplingConfig,
+    stopping: StoppingConfig,
+    on_event: &Channel,
+    cancel: &CancelToken,
+) -> Result<(), Error> {
+    let mut session = session.lock().unwrap();
+    let mut pipeline = session.ask(&prompt, &sampling, &stopping, cancel)?;
+    let result = emit_generations(on_event, &mut pipeline);
+    let outcome = pipeline.outcome()?;
+    drop(pipeline);
+    session.finish_turn(prompt, outcome);
+    result
+}
+
+#[tauri::command]
+fn ask_session(
+    state: tauri::State<'_, State>,
+    id: SessionId,
+    prompt: String,
+    sampling: Option<SamplingConfig>,
+    stopping: Option<StoppingConfig>,
+    on_event: Channel,
+) -> Result<RequestId, Error> {
+    debug!("Asking session {id}: {prompt}");
+    let sampling = sampling.unwrap_or_default();
+    let stopping = stopping.unwrap_or_default();
+    let session = get_session(&state, id)?.session;
+    let job_prompt = prompt.clone();
+    state.jobs.submit(
+        JobKind::Ask,
+        job_prompt,
+        on_event,
+        Box::new(move |on_event, cancel| {
+            run_ask(session, prompt, sampling, stopping, on_event, cancel)
+        }),
+    )
+}
+
+/// Waits for the answer in progress, if any, before forgetting the turns.
+#[tauri::command]
+async fn reset_session(app: tauri::AppHandle, id: SessionId) -> Result<(), Error> {
+    let session = get_session(&app.state::<State>(), id)?.session;
+    tokio::task::spawn_blocking(move || session.lock().unwrap().reset()).await?;
     Ok(())
 }
 
+#[tauri::command(async)]
+fn session_history(state: tauri::State<'_, State>, id: SessionId) -> Result<Vec<Turn>, Error> {
+    Ok(get_session(&state, id)?.turns.lock().unwrap().clone())
+}
+
+#[tauri::command]
+fn close_session(state: tauri::State<'_, State>, id: SessionId) -> Result<(), Error> {
+    match state.sessions.lock().unwrap().remove(&id) {
+        Some(_) => Ok(()),
+        None => Err(Error::InputError(format!("Session {id} does not exist"))),
+    }
+}
+
 #[allow(unused_variables)]
 fn cache(path: &std::path::Path) -> hf_hub::Cache {
     #[cfg(not(mobile))]
@@ -199,36 +1079,122 @@ pub fn run() {
         )
         .plugin(tauri_plugin_shell::init())
         .invoke_handler(tauri::generate_handler![
-            generate, stop, copy_image, open_image
+            generate,
+            stop,
+            list_jobs,
+            configure_jobs,
+            import_image,
+            list_images,
+            rename_image,
+            delete_image,
+            open_image,
+            load_model,
+            model_status,
+            get_model,
+            set_model,
+            get_dtype,
+            set_dtype,
+            get_devices,
+            set_device,
+            get_preprocess,
+            set_preprocess,
+            download_model,
+            cancel_download,
+            clear_embedding_cache,
+            batch_generate,
+            pause_batch,
+            resume_batch,
+            create_session,
+            ask_session,
+            reset_session,
+            session_history,
+            close_session
         ])
         .setup(move |app| {
             info!("Start the run");
-            info!(
-                "avx: {}, neon: {}, simd128: {}, f16c: {}",
-                candle::utils::with_avx(),
-                candle::utils::with_neon(),
-                candle::utils::with_simd128(),
-                candle::utils::with_f16c()
-            );
+            info!("cpu features: {:?}", CpuFeatures::detect());
+            let settings_path = app
+                .path()
+                .app_config_dir()
+                .ok()
+                .map(|dir| Settings::path(&dir));
+            let settings = settings_path
+                .as_deref()
+                .map(Settings::load)
+                .unwrap_or_default();
+            info!("settings: {:?}", settings);
             let path = app.path().local_data_dir().expect("Have a local data dir");
             info!("path: {:?}", path);
             let cache = cache(&path);
-            let device = if candle::utils::cuda_is_available() {
-                Device::new_cuda(0)?
-            // Simulator doesn't support MPS (Metal Performance Shader).
-            } else if candle::utils::metal_is_available() && TARGET != "aarch64-apple-ios-sim" {
-                Device::Cpu // For now, use CPU
-            } else {
+            let device = utils::device(settings.device).unwrap_or_else(|e| {
+                error!("Could not use device {}: {:?}", settings.device, e);
                 Device::Cpu
-            };
+            });
             info!("using device: {:?}", device);
+            let data_dir = app.path().app_data_dir()?;
+            let embeddings_dir = Some(data_dir.join("embeddings"));
+            let library = ImageLibrary::open(data_dir.join("images"))?;
             app.manage(State {
                 cache,
-                device,
-                tx: tokio::sync::Mutex::new(None),
+                device: std::sync::Mutex::new(device),
+                settings: std::sync::Mutex::new(settings),
+                settings_path,
+                model: tokio::sync::Mutex::new(None),
+                model_status: std::sync::Mutex::new(ModelStatus::NotLoaded),
+                embeddings: std::sync::Mutex::new(EmbeddingCache::new(
+                    EMBEDDING_CACHE_CAPACITY,
+                    embeddings_dir,
+                    EMBEDDING_DISK_CAPACITY,
+                )),
+                library: std::sync::Mutex::new(library),
+                sessions: std::sync::Mutex::new(HashMap::new()),
+                next_session_id: AtomicU64::new(1),
+                jobs: Scheduler::new(SchedulerConfig::default()),
+                download: std::sync::Mutex::new(None),
+                batches: std::sync::Mutex::new(HashMap::new()),
             });
             Ok(())
         })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn error_payload_carries_details() {
+        let error = Error::ModelNotFound {
+            model: "vikhyatk/moondream2".to_string(),
+            missing: vec!["model.safetensors".to_string()],
+        };
+        let payload = serde_json::to_value(ErrorPayload::from(&error)).unwrap();
+        assert_eq!(
+            payload,
+            json!({
+                "code": "model_not_found",
+                "message": "Model vikhyatk/moondream2 was not found, missing model.safetensors",
+                "details": {
+                    "model_id": "vikhyatk/moondream2",
+                    "files": ["model.safetensors"],
+                },
+                "retryable": false,
+            })
+        );
+    }
+
+    #[test]
+    fn error_payload_omits_missing_details() {
+        let payload = serde_json::to_value(ErrorPayload::from(&Error::Cancelled)).unwrap();
+        assert_eq!(
+            payload,
+            json!({
+                "code": "cancelled",
+                "message": "Cancelled",
+                "retryable": true,
+            })
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::{embeddings::content_hash, Error};
+
+const INDEX_FILE: &str = "index.json";
+const THUMBNAILS_DIR: &str = "thumbnails";
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// An image imported into the library.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LibraryImage {
+    /// Content hash of the image, which is also its file name.
+    pub id: String,
+    /// Name shown to the user, the original file name unless renamed.
+    pub name: String,
+    pub path: PathBuf,
+    pub thumbnail: PathBuf,
+}
+
+/// An image read and thumbnailed, ready for [`ImageLibrary::import`]. Reading
+/// does the slow part without holding the library.
+pub struct PendingImport {
+    id: String,
+    name: String,
+    extension: &'static str,
+    bytes: Vec<u8>,
+    /// PNG encoded thumbnail.
+    thumbnail: Vec<u8>,
+}
+
+impl PendingImport {
+    pub fn read(src: &Path) -> Result<Self, Error> {
+        let bytes = std::fs::read(src).map_err(Error::file(src))?;
+        let id = content_hash(&bytes);
+        let format = image::guess_format(&bytes)?;
+        let extension = format.extensions_str().first().copied().unwrap_or("img");
+        let mut thumbnail = vec![];
+        image::load_from_memory_with_format(&bytes, format)?
+            .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+            .write_to(&mut Cursor::new(&mut thumbnail), ImageFormat::Png)?;
+        let name = src
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| id.clone());
+        Ok(Self {
+            id,
+            name,
+            extension,
+            bytes,
+            thumbnail,
+        })
+    }
+}
+
+/// Images imported by the user, kept in the app data dir and named by content
+/// hash so two pictures sharing a file name never shadow each other. Names and
+/// order are kept in an index next to the images.
+pub struct ImageLibrary {
+    dir: PathBuf,
+    images: Vec<LibraryImage>,
+}
+
+impl ImageLibrary {
+    /// Opens the library in `dir`, creating it if needed. An unreadable index
+    /// is rebuilt from the images found in `dir`.
+    pub fn open(dir: PathBuf) -> Result<Self, Error> {
+        let thumbnails = dir.join(THUMBNAILS_DIR);
+        std::fs::create_dir_all(&thumbnails).map_err(Error::file(&thumbnails))?;
+        let index = dir.join(INDEX_FILE);
+        if !index.exists() {
+            return Ok(Self {
+                dir,
+                images: vec![],
+            });
+        }
+        let images: Result<Vec<LibraryImage>, Error> = std::fs::read_to_string(&index)
+            .map_err(Error::file(&index))
+            .and_then(|images| Ok(serde_json::from_str(&images)?));
+        let images = match images {
+            Ok(images) => images,
+            Err(e) => {
+                tracing::error!("Could not read image library index {:?}: {:?}", index, e);
+                rebuild_index(&dir)
+            }
+        };
+        Ok(Self { dir, images })
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let index = self.dir.join(INDEX_FILE);
+        let images = serde_json::to_string_pretty(&self.images)?;
+        std::fs::write(&index, images).map_err(Error::file(&index))
+    }
+
+    pub fn list(&self) -> &[LibraryImage] {
+        &self.images
+    }
+
+    pub fn get(&self, id: &str) -> Result<&LibraryImage, Error> {
+        self.images
+            .iter()
+            .find(|image| image.id == id)
+            .ok_or_else(|| Error::ImageNotFound(id.to_string()))
+    }
+
+    /// Adds an image read by [`PendingImport::read`]. Importing the same picture
+    /// twice returns the existing entry.
+    pub fn import(&mut self, pending: PendingImport) -> Result<LibraryImage, Error> {
+        let PendingImport {
+            id,
+            name,
+            extension,
+            bytes,
+            thumbnail: thumbnail_png,
+        } = pending;
+        if let Ok(image) = self.get(&id) {
+            tracing::debug!("Image {} already imported as {}", name, id);
+            return Ok(image.clone());
+        }
+        let thumbnail = self.dir.join(THUMBNAILS_DIR).join(format!("{id}.png"));
+        std::fs::write(&thumbnail, thumbnail_png).map_err(Error::file(&thumbnail))?;
+        let path = self.dir.join(format!("{id}.{extension}"));
+        if let Err(e) = std::fs::write(&path, &bytes) {
+            let _ = std::fs::remove_file(&thumbnail);
+            return Err(Error::file(&path)(e));
+        }
+
+        let image = LibraryImage {
+            id,
+            name,
+            path,
+            thumbnail,
+        };
+        tracing::debug!("Imported {:?}", image);
+        self.images.push(image.clone());
+        self.save()?;
+        Ok(image)
+    }
+
+    pub fn rename(&mut self, id: &str, name: String) -> Result<LibraryImage, Error> {
+        if name.trim().is_empty() {
+            return Err(Error::InputError("Image name is empty".to_string()));
+        }
+        let image = self
+            .images
+            .iter_mut()
+            .find(|image| image.id == id)
+            .ok_or_else(|| Error::ImageNotFound(id.to_string()))?;
+        image.name = name;
+        let image = image.clone();
+        self.save()?;
+        Ok(image)
+    }
+
+    /// Removes the image and its thumbnail from disk.
+    pub fn delete(&mut self, id: &str) -> Result<(), Error> {
+        let pos = self
+            .images
+            .iter()
+            .position(|image| image.id == id)
+            .ok_or_else(|| Error::ImageNotFound(id.to_string()))?;
+        let image = self.images.remove(pos);
+        for path in [&image.path, &image.thumbnail] {
+            if path.exists() {
+                std::fs::remove_file(path).map_err(Error::file(path))?;
+            }
+        }
+        self.save()
+    }
+}
+
+/// Entries for the images in `dir` that have a thumbnail, named after their
+/// file since the original names were in the index.
+fn rebuild_index(dir: &Path) -> Vec<LibraryImage> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!("Could not list image library {:?}: {:?}", dir, e);
+            return vec![];
+        }
+    };
+    let mut images: Vec<LibraryImage> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_string_lossy().to_string();
+            let id = path.file_stem()?.to_string_lossy().to_string();
+            let thumbnail = dir.join(THUMBNAILS_DIR).join(format!("{id}.png"));
+            thumbnail.exists().then_some(LibraryImage {
+                id,
+                name,
+                path,
+                thumbnail,
+            })
+        })
+        .collect();
+    images.sort_by(|a, b| a.name.cmp(&b.name));
+    tracing::info!(
+        "Rebuilt the image library index with {} images",
+        images.len()
+    );
+    images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebuilds_an_unreadable_index() {
+        let dir = std::env::temp_dir().join(format!("moondream-library-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(THUMBNAILS_DIR)).unwrap();
+        std::fs::write(dir.join("abc.png"), b"image").unwrap();
+        std::fs::write(dir.join(THUMBNAILS_DIR).join("abc.png"), b"thumbnail").unwrap();
+        // No thumbnail, so not an image the library imported.
+        std::fs::write(dir.join("stray.txt"), b"stray").unwrap();
+        std::fs::write(dir.join(INDEX_FILE), b"{ not json").unwrap();
+
+        let library = ImageLibrary::open(dir.clone()).unwrap();
+        let ids: Vec<&str> = library
+            .list()
+            .iter()
+            .map(|image| image.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["abc"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,85 +1,425 @@
-use crate::{utils::load_image, Error, Generation, Token};
+use crate::{
+    cancel::CancelToken,
+    embeddings::{content_hash, EmbeddingCache},
+    preprocess::{decode_frames, views, PreprocessConfig, PreprocessSettings, Region},
+    utils::ImageInput,
+    Error, Generation, GenerationDetails, ImagePart, Token,
+};
 use candle::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::{
-    generation::LogitsProcessor,
-    models::moondream::{Config, Model},
+    generation::{LogitsProcessor, Sampling},
+    models::{mixformer, moondream, quantized_mixformer, quantized_moondream},
+    quantized_var_builder,
 };
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tokenizers::Tokenizer;
 
-fn build_model_and_tokenizer(
-    api: &hf_hub::api::sync::Api,
-    device: &Device,
-) -> Result<(Model, Tokenizer), Error> {
-    let model_id = "vikhyatk/moondream2".to_string();
-    let repo = api.repo(hf_hub::Repo::new(model_id, hf_hub::RepoType::Model));
-    let model_file = repo.get("model.safetensors")?;
-    let tokenizer = repo.get("tokenizer.json")?;
-    let tokenizer = Tokenizer::from_file(tokenizer)?;
-    let config = Config::v2();
+/// Load state of the resident model, emitted to the frontend as `model-status`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ModelStatus {
+    NotLoaded,
+    /// Hub files that have to be downloaded before the model can load.
+    NotDownloaded {
+        missing: Vec<String>,
+    },
+    Downloading {
+        file: String,
+        progress: f32,
+    },
+    Loading {
+        message: String,
+        progress: f32,
+    },
+    Ready,
+    Failed {
+        error: String,
+    },
+}
 
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], DType::F16, device)? };
-    let model = Model::new(&config, vb)?;
-    tracing::debug!("Model and tokenizer loaded");
-    Ok((model, tokenizer))
+impl ModelStatus {
+    fn loading(message: &str, progress: f32) -> Self {
+        Self::Loading {
+            message: message.to_string(),
+            progress,
+        }
+    }
 }
 
-fn get_image_embeddings(image: String, device: &Device) -> Result<Tensor, Error> {
-    tracing::debug!("Loading image {}", image);
-    let image = load_image(&image)?
-        .to_dtype(DType::F16)?
-        .to_device(device)?
-        .unsqueeze(0)?;
-    Ok(image)
+/// Sampling settings for a single generation. The defaults decode greedily.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SamplingConfig {
+    /// Softmax temperature, `None` or `0.0` selects the most likely token.
+    pub temperature: Option<f64>,
+    /// Nucleus sampling probability cutoff.
+    pub top_p: Option<f64>,
+    /// Only sample among the `top_k` most likely tokens.
+    pub top_k: Option<usize>,
+    pub seed: u64,
+    /// Penalty applied to tokens already generated, `1.0` disables it.
+    pub repeat_penalty: f32,
+    /// How many of the last generated tokens the repeat penalty looks at.
+    pub repeat_last_n: usize,
 }
 
-pub fn build_pipeline(
-    prompt: String,
-    image: String,
-    device: &Device,
-    cache: &hf_hub::Cache,
-) -> Result<Pipeline, Error> {
-    let api = hf_hub::api::sync::ApiBuilder::from_cache(cache.clone()).build()?;
-    let (model, tokenizer) = build_model_and_tokenizer(&api, device)?;
-    let prompt = format!("\n\nQuestion: {}\nAnswer:", prompt);
-    let tokens = tokenizer.encode(prompt, true)?;
-    if tokens.is_empty() {
-        return Err(Error::InputError("Prompt is empty".to_string()));
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            seed: 0,
+            repeat_penalty: 1.0,
+            repeat_last_n: 64,
+        }
     }
-    let tokens = tokens.get_ids().to_vec();
-    let image_embeds = get_image_embeddings(image, device)?.apply(model.vision_encoder())?;
-    tracing::debug!("Generated image embeddings: {:?}", image_embeds);
-    Pipeline::new(model, tokenizer, device, &tokens, image_embeds)
 }
 
-pub struct PipelineIter<'a> {
-    pipeline: &'a mut Pipeline,
-    tokens: Vec<u32>,
-    image_embeds: Tensor,
-    generated_tokens: Vec<u32>,
-    last: bool,
-    i: usize,
+impl SamplingConfig {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.temperature.is_some_and(|t| t < 0.0) {
+            return Err(Error::InputError(
+                "Temperature must not be negative".to_string(),
+            ));
+        }
+        if self.top_p.is_some_and(|p| p <= 0.0 || p > 1.0) {
+            return Err(Error::InputError("Top p must be in (0, 1]".to_string()));
+        }
+        if self.top_k == Some(0) {
+            return Err(Error::InputError("Top k must be positive".to_string()));
+        }
+        if self.repeat_penalty <= 0.0 {
+            return Err(Error::InputError(
+                "Repeat penalty must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn logits_processor(&self) -> LogitsProcessor {
+        let sampling = match self.temperature {
+            Some(temperature) if temperature > 0.0 => match (self.top_k, self.top_p) {
+                (None, None) => Sampling::All { temperature },
+                (Some(k), None) => Sampling::TopK { k, temperature },
+                (None, Some(p)) => Sampling::TopP { p, temperature },
+                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+            },
+            _ => Sampling::ArgMax,
+        };
+        LogitsProcessor::from_sampling(self.seed, sampling)
+    }
+}
+
+/// When to end a generation besides the model emitting `<|endoftext|>`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StoppingConfig {
+    pub max_new_tokens: usize,
+    /// Strings that end the generation as soon as they appear in the decoded
+    /// text. They are not part of the returned text.
+    pub stop: Vec<String>,
+}
+
+impl Default for StoppingConfig {
+    fn default() -> Self {
+        Self {
+            max_new_tokens: 512,
+            stop: vec![],
+        }
+    }
+}
+
+impl StoppingConfig {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.max_new_tokens == 0 {
+            return Err(Error::InputError(
+                "Max new tokens must be positive".to_string(),
+            ));
+        }
+        if self.stop.iter().any(|s| s.is_empty()) {
+            return Err(Error::InputError(
+                "Stop sequences must not be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Why a generation ended, reported on its last [`Generation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Eos,
+    Length,
+    StopSequence,
+    Cancelled,
+    Error,
+}
+
+/// Moondream architecture revision, selecting the candle model config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigVariant {
+    #[default]
+    V2,
+}
+
+impl ConfigVariant {
+    fn config(&self) -> moondream::Config {
+        match self {
+            ConfigVariant::V2 => moondream::Config::v2(),
+        }
+    }
+
+    /// Input size and normalization the vision encoder was trained with.
+    fn preprocess(&self, settings: PreprocessSettings) -> PreprocessConfig {
+        match self {
+            ConfigVariant::V2 => PreprocessConfig {
+                settings,
+                ..PreprocessConfig::default()
+            },
+        }
+    }
+}
+
+/// Data type the model runs in. Sampling always happens in F32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComputeDtype {
+    F32,
+    Bf16,
+    F16,
+}
+
+impl ComputeDtype {
+    /// F16 on CPU is slow and loses precision, so the CPU runs in F32 and GPUs
+    /// in half precision.
+    pub fn for_device(device: &Device) -> Self {
+        match device {
+            Device::Cpu => ComputeDtype::F32,
+            Device::Cuda(_) => ComputeDtype::Bf16,
+            Device::Metal(_) => ComputeDtype::F16,
+        }
+    }
+
+    pub fn dtype(&self) -> DType {
+        match self {
+            ComputeDtype::F32 => DType::F32,
+            ComputeDtype::Bf16 => DType::BF16,
+            ComputeDtype::F16 => DType::F16,
+        }
+    }
+}
+
+/// Encoding of the weights file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightsFormat {
+    /// Full precision safetensors, loaded in the compute dtype.
+    #[default]
+    Safetensors,
+    /// Quantized GGUF weights (q4, q8...), run by candle's quantized moondream.
+    Gguf,
+}
+
+/// Where the model files come from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ModelSource {
+    /// Downloaded from the Hugging Face hub into the hf_hub cache.
+    #[default]
+    Hub,
+    /// Read from a directory on disk, without any network access.
+    Local { dir: PathBuf },
+    /// Like `Local`, with `dir` relative to the app's bundled resources.
+    Bundled { dir: PathBuf },
+}
+
+/// Which model files to load. Pinning `revision` to a commit keeps builds
+/// reproducible.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ModelDescriptor {
+    pub source: ModelSource,
+    pub repo_id: String,
+    /// Branch, tag or commit on the hub, `None` for the default branch.
+    pub revision: Option<String>,
+    pub weights_file: String,
+    pub weights_format: WeightsFormat,
+    pub tokenizer_file: String,
+    pub config: ConfigVariant,
 }
 
-pub struct Pipeline {
+impl Default for ModelDescriptor {
+    fn default() -> Self {
+        Self {
+            source: ModelSource::Hub,
+            repo_id: "vikhyatk/moondream2".to_string(),
+            revision: None,
+            weights_file: "model.safetensors".to_string(),
+            weights_format: WeightsFormat::Safetensors,
+            tokenizer_file: "tokenizer.json".to_string(),
+            config: ConfigVariant::V2,
+        }
+    }
+}
+
+impl ModelDescriptor {
+    pub(crate) fn repo(&self) -> hf_hub::Repo {
+        match &self.revision {
+            Some(revision) => hf_hub::Repo::with_revision(
+                self.repo_id.clone(),
+                hf_hub::RepoType::Model,
+                revision.clone(),
+            ),
+            None => hf_hub::Repo::new(self.repo_id.clone(), hf_hub::RepoType::Model),
+        }
+    }
+
+    /// Short digest of what decides the vision encoder weights, so embeddings
+    /// computed by another model are not reused.
+    pub fn fingerprint(&self) -> String {
+        let model = serde_json::json!({
+            "source": self.source,
+            "repo_id": self.repo_id,
+            "revision": self.revision,
+            "weights_file": self.weights_file,
+            "weights_format": self.weights_format,
+            "config": self.config,
+        });
+        content_hash(model.to_string().as_bytes())[..8].to_string()
+    }
+
+    /// Turns a `Bundled` source into a `Local` one rooted at `resource_dir`.
+    pub fn resolve_bundled(mut self, resource_dir: Option<&Path>) -> Self {
+        if let ModelSource::Bundled { dir } = &self.source {
+            if let Some(resource_dir) = resource_dir {
+                self.source = ModelSource::Local {
+                    dir: resource_dir.join(dir),
+                };
+            }
+        }
+        self
+    }
+
+    /// Paths of the weights and tokenizer files. Hub files have to be in the
+    /// cache already, `Downloader` is what puts them there.
+    fn files(&self, cache: &hf_hub::Cache) -> Result<(PathBuf, PathBuf), Error> {
+        match &self.source {
+            ModelSource::Hub => {
+                let repo = cache.repo(self.repo());
+                match (repo.get(&self.weights_file), repo.get(&self.tokenizer_file)) {
+                    (Some(weights), Some(tokenizer)) => Ok((weights, tokenizer)),
+                    (weights, tokenizer) => Err(Error::ModelNotFound {
+                        model: self.repo_id.clone(),
+                        missing: [
+                            (weights, &self.weights_file),
+                            (tokenizer, &self.tokenizer_file),
+                        ]
+                        .into_iter()
+                        .filter(|(path, _)| path.is_none())
+                        .map(|(_, file)| file.clone())
+                        .collect(),
+                    }),
+                }
+            }
+            ModelSource::Local { dir } | ModelSource::Bundled { dir } => {
+                let weights = dir.join(&self.weights_file);
+                let tokenizer = dir.join(&self.tokenizer_file);
+                let missing: Vec<String> = [&weights, &tokenizer]
+                    .iter()
+                    .filter(|path| !path.is_file())
+                    .map(|path| path.to_string_lossy().to_string())
+                    .collect();
+                if !missing.is_empty() {
+                    return Err(Error::ModelNotFound {
+                        model: dir.to_string_lossy().to_string(),
+                        missing,
+                    });
+                }
+                Ok((weights, tokenizer))
+            }
+        }
+    }
+}
+
+/// Moondream weights, either full precision or quantized.
+enum Model {
+    Full(moondream::Model),
+    Quantized(quantized_moondream::Model),
+}
+
+/// Text decoder of either model flavour, with its own KV cache.
+#[derive(Clone)]
+pub enum TextModel {
+    Full(mixformer::MixFormerSequentialForCausalLM),
+    Quantized(quantized_mixformer::MixFormerSequentialForCausalLM),
+}
+
+impl TextModel {
+    fn forward(&mut self, xs: &Tensor) -> candle::Result<Tensor> {
+        match self {
+            TextModel::Full(model) => model.forward(xs),
+            TextModel::Quantized(model) => model.forward(xs),
+        }
+    }
+
+    fn forward_with_img(
+        &mut self,
+        bos_token: &Tensor,
+        xs: &Tensor,
+        img_embeds: &Tensor,
+    ) -> candle::Result<Tensor> {
+        match self {
+            TextModel::Full(model) => model.forward_with_img(bos_token, xs, img_embeds),
+            TextModel::Quantized(model) => model.forward_with_img(bos_token, xs, img_embeds),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match self {
+            TextModel::Full(model) => model.clear_kv_cache(),
+            TextModel::Quantized(model) => model.clear_kv_cache(),
+        }
+    }
+}
+
+/// Model and tokenizer kept alive across `generate` calls.
+pub struct Moondream {
     model: Model,
-    device: Device,
     tokenizer: Tokenizer,
-    logits_processor: LogitsProcessor,
-    tokens: Vec<u32>,
-    image_embeds: Tensor,
+    device: Device,
+    dtype: DType,
     special_token: u32,
+    preprocess: RwLock<PreprocessConfig>,
+    /// [`ModelDescriptor::fingerprint`] of the loaded model.
+    fingerprint: String,
 }
 
-impl Pipeline {
-    fn new(
-        model: Model,
-        tokenizer: Tokenizer,
+impl Moondream {
+    pub fn load<F>(
+        descriptor: &ModelDescriptor,
         device: &Device,
-        tokens: &Vec<u32>,
-        image_embeds: Tensor,
-    ) -> Result<Self, Error> {
-        let logits_processor = LogitsProcessor::new(0, None, None);
+        dtype: DType,
+        preprocess: PreprocessSettings,
+        cache: &hf_hub::Cache,
+        cancel: &CancelToken,
+        on_progress: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(ModelStatus),
+    {
+        cancel.check()?;
+        on_progress(ModelStatus::loading("Fetching model files", 0.0));
+        let (model_file, tokenizer) = descriptor.files(cache)?;
+        cancel.check()?;
+        on_progress(ModelStatus::loading("Loading tokenizer", 0.5));
+        let tokenizer = Tokenizer::from_file(tokenizer)?;
         // Moondream tokenizer bos_token and eos_token is "<|endoftext|>"
         // https://huggingface.co/vikhyatk/moondream2/blob/main/special_tokens_map.json
         let special_token = match tokenizer.get_vocab(true).get("<|endoftext|>") {
@@ -90,87 +430,1144 @@ impl Pipeline {
                 ))
             }
         };
+        cancel.check()?;
+        on_progress(ModelStatus::loading("Loading model weights", 0.6));
+        let config = descriptor.config.config();
+        let model = match descriptor.weights_format {
+            WeightsFormat::Safetensors => {
+                let vb =
+                    unsafe { VarBuilder::from_mmaped_safetensors(&[model_file], dtype, device)? };
+                Model::Full(moondream::Model::new(&config, vb)?)
+            }
+            WeightsFormat::Gguf => {
+                let vb = quantized_var_builder::VarBuilder::from_gguf(&model_file, device)?;
+                Model::Quantized(quantized_moondream::Model::new(&config, vb)?)
+            }
+        };
+        // Reading the weights can take a while, don't keep a model nobody waits for.
+        cancel.check()?;
+        tracing::debug!(
+            "Model and tokenizer loaded from {:?} as {:?}",
+            descriptor,
+            dtype
+        );
         Ok(Self {
             model,
             tokenizer,
-            logits_processor,
             device: device.clone(),
-            tokens: tokens.clone(),
+            dtype,
             special_token,
-            image_embeds,
+            preprocess: RwLock::new(descriptor.config.preprocess(preprocess)),
+            fingerprint: descriptor.fingerprint(),
+        })
+    }
+
+    /// A handle on the text model with its own, empty KV cache. The weights are
+    /// shared, so several generations can run side by side.
+    pub fn text_model(&self) -> TextModel {
+        let mut text_model = match &self.model {
+            Model::Full(model) => TextModel::Full(model.text_model.clone()),
+            Model::Quantized(model) => TextModel::Quantized(model.text_model.clone()),
+        };
+        text_model.clear_kv_cache();
+        text_model
+    }
+
+    /// Data type image tensors and embeddings are in. The quantized model
+    /// dequantizes its weights to F32 whatever the compute dtype.
+    fn image_dtype(&self) -> DType {
+        match self.model {
+            Model::Full(_) => self.dtype,
+            Model::Quantized(_) => DType::F32,
+        }
+    }
+
+    /// Changes how images are resized. Embeddings are cached per preprocessing,
+    /// so images get encoded again the next time they are used.
+    pub fn set_preprocess(&self, settings: PreprocessSettings) {
+        self.preprocess.write().unwrap().settings = settings;
+    }
+
+    fn encode(&self, image: &Tensor) -> candle::Result<Tensor> {
+        match &self.model {
+            Model::Full(model) => image.apply(model.vision_encoder()),
+            Model::Quantized(model) => image.apply(&model.vision_encoder),
+        }
+    }
+}
+
+fn get_image_embeddings(
+    image: &image::DynamicImage,
+    preprocess: &PreprocessConfig,
+    device: &Device,
+    dtype: DType,
+    cancel: &CancelToken,
+) -> Result<Tensor, Error> {
+    let image = preprocess.to_tensor(image)?;
+    cancel.check()?;
+    let image = image.to_dtype(dtype)?.to_device(device)?.unsqueeze(0)?;
+    Ok(image)
+}
+
+/// Vision encoder output together with how long it took to obtain.
+#[derive(Debug, Clone)]
+pub struct EncodedImage {
+    embeds: Tensor,
+    encode_time: Duration,
+    /// Frame of the input the embeddings are for.
+    frame: usize,
+    /// Tile of the frame the embeddings are for, if the input was tiled.
+    tile: Option<Region>,
+}
+
+impl EncodedImage {
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn tile(&self) -> Option<Region> {
+        self.tile
+    }
+}
+
+/// Runs the vision encoder over the selected frames of `image`, or over each of
+/// their tiles, reusing cached embeddings when the same picture was encoded
+/// before.
+pub fn encode_image(
+    image: &ImageInput,
+    moondream: &Moondream,
+    embeddings: &Mutex<EmbeddingCache>,
+    cancel: &CancelToken,
+) -> Result<Vec<EncodedImage>, Error> {
+    let mut start = Instant::now();
+    cancel.check()?;
+    tracing::debug!("Loading image {}", image);
+    let bytes = image.bytes()?;
+    let hash = content_hash(&bytes);
+    let frames = decode_frames(&bytes, image.frames)?;
+    let views = views(frames, image.crop, image.tiling)?;
+    let preprocess = moondream.preprocess.read().unwrap().clone();
+    let mut encoded = Vec::with_capacity(views.len());
+    for view in views {
+        let key = embedding_key(
+            &hash,
+            moondream,
+            &preprocess,
+            view.frame,
+            view.tile.or(image.crop),
+        );
+        // The cache is only locked to look up and store, other jobs keep
+        // encoding meanwhile.
+        let cached = embeddings.lock().unwrap().get(&key, &moondream.device);
+        let image_embeds = match cached {
+            Some(embeds) => {
+                tracing::debug!("Image embeddings cache hit for {}", key);
+                embeds
+            }
+            None => {
+                let image = get_image_embeddings(
+                    &view.image,
+                    &preprocess,
+                    &moondream.device,
+                    moondream.image_dtype(),
+                    cancel,
+                )?;
+                cancel.check()?;
+                let embeds = moondream.encode(&image)?;
+                embeddings.lock().unwrap().put(&key, embeds.clone());
+                embeds
+            }
+        };
+        // Cached embeddings may come from a run with another dtype or device.
+        let image_embeds = image_embeds
+            .to_dtype(moondream.image_dtype())?
+            .to_device(&moondream.device)?;
+        cancel.check()?;
+        tracing::debug!(
+            "Generated image embeddings for frame {} tile {:?}: {:?}",
+            view.frame,
+            view.tile,
+            image_embeds
+        );
+        encoded.push(EncodedImage {
+            embeds: image_embeds,
+            encode_time: start.elapsed(),
+            frame: view.frame,
+            tile: view.tile,
+        });
+        start = Instant::now();
+    }
+    Ok(encoded)
+}
+
+/// Embedding cache key of a frame, or of a region of it, as encoded by
+/// `moondream`.
+fn embedding_key(
+    hash: &str,
+    moondream: &Moondream,
+    preprocess: &PreprocessConfig,
+    frame: usize,
+    region: Option<Region>,
+) -> String {
+    let mut key = format!(
+        "{}-{}-{}-{}",
+        hash,
+        moondream.fingerprint,
+        preprocess.fingerprint(),
+        frame
+    );
+    if let Some(region) = region {
+        key.push_str(&format!(
+            "-{}_{}_{}_{}",
+            region.x, region.y, region.width, region.height
+        ));
+    }
+    key
+}
+
+/// An input of [`encode_images`], either found in the cache or preprocessed
+/// and waiting for the vision encoder.
+enum PreparedImage {
+    Cached(EncodedImage),
+    Pending {
+        key: String,
+        frame: usize,
+        tile: Option<Region>,
+        pixels: Tensor,
+    },
+}
+
+fn prepare_image(
+    input: &ImageInput,
+    preprocess: &PreprocessConfig,
+    moondream: &Moondream,
+    embeddings: &Mutex<EmbeddingCache>,
+) -> Result<PreparedImage, Error> {
+    let bytes = input.bytes()?;
+    let hash = content_hash(&bytes);
+    let frames = decode_frames(&bytes, input.frames)?;
+    let view = views(frames, input.crop, input.tiling)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::InputError("No frame selected".to_string()))?;
+    let key = embedding_key(
+        &hash,
+        moondream,
+        preprocess,
+        view.frame,
+        view.tile.or(input.crop),
+    );
+    let cached = embeddings.lock().unwrap().get(&key, &moondream.device);
+    if let Some(embeds) = cached {
+        return Ok(PreparedImage::Cached(EncodedImage {
+            embeds: embeds
+                .to_dtype(moondream.image_dtype())?
+                .to_device(&moondream.device)?,
+            encode_time: Duration::ZERO,
+            frame: view.frame,
+            tile: view.tile,
+        }));
+    }
+    Ok(PreparedImage::Pending {
+        key,
+        frame: view.frame,
+        tile: view.tile,
+        pixels: preprocess.to_tensor(&view.image)?,
+    })
+}
+
+/// Runs the vision encoder once over the first view of every input, stacked in
+/// a single batch. Cached inputs are not encoded again, and an input that fails
+/// to load only fails its own result.
+pub fn encode_images(
+    inputs: &[ImageInput],
+    moondream: &Moondream,
+    embeddings: &Mutex<EmbeddingCache>,
+    cancel: &CancelToken,
+) -> Result<Vec<Result<EncodedImage, Error>>, Error> {
+    let start = Instant::now();
+    let preprocess = moondream.preprocess.read().unwrap().clone();
+    let mut results: Vec<Option<Result<EncodedImage, Error>>> =
+        inputs.iter().map(|_| None).collect();
+    let mut pending = vec![];
+    for (index, input) in inputs.iter().enumerate() {
+        cancel.check()?;
+        match prepare_image(input, &preprocess, moondream, embeddings) {
+            Ok(PreparedImage::Cached(encoded)) => results[index] = Some(Ok(encoded)),
+            Ok(PreparedImage::Pending {
+                key,
+                frame,
+                tile,
+                pixels,
+            }) => pending.push((index, key, frame, tile, pixels)),
+            Err(e) => results[index] = Some(Err(e)),
+        }
+    }
+    if !pending.is_empty() {
+        cancel.check()?;
+        let pixels: Vec<&Tensor> = pending.iter().map(|(.., pixels)| pixels).collect();
+        let pixels = Tensor::stack(&pixels, 0)?
+            .to_dtype(moondream.image_dtype())?
+            .to_device(&moondream.device)?;
+        tracing::debug!("Encoding {} images in one batch", pending.len());
+        let embeds = moondream.encode(&pixels)?;
+        let encode_time = start.elapsed();
+        for (row, (index, key, frame, tile, _)) in pending.into_iter().enumerate() {
+            let embeds = embeds.narrow(0, row, 1)?;
+            embeddings.lock().unwrap().put(&key, embeds.clone());
+            results[index] = Some(Ok(EncodedImage {
+                embeds,
+                encode_time,
+                frame,
+                tile,
+            }));
+        }
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| result.expect("every input has a result"))
+        .collect())
+}
+
+fn encode_prompt(prompt: &str, tokenizer: &Tokenizer) -> Result<Vec<u32>, Error> {
+    let prompt = format!("\n\nQuestion: {}\nAnswer:", prompt);
+    let tokens = tokenizer.encode(prompt, true)?;
+    if tokens.is_empty() {
+        return Err(Error::InputError("Prompt is empty".to_string()));
+    }
+    Ok(tokens.get_ids().to_vec())
+}
+
+/// Builds a single question pipeline about an image from [`encode_image`],
+/// decoding with `text_model`, obtained from [`Moondream::text_model`].
+pub fn build_pipeline<'m>(
+    prompt: &str,
+    image: EncodedImage,
+    moondream: &'m Moondream,
+    text_model: &'m mut TextModel,
+    sampling: &SamplingConfig,
+    stopping: &StoppingConfig,
+    cancel: &CancelToken,
+) -> Result<Pipeline<'m>, Error> {
+    let tokens = encode_prompt(prompt, &moondream.tokenizer)?;
+    Pipeline::new(
+        text_model,
+        &moondream.tokenizer,
+        &moondream.device,
+        moondream.special_token,
+        tokens,
+        Some(image),
+        sampling,
+        stopping,
+        cancel,
+    )
+}
+
+/// Answer to one of the sequences of [`generate_batch`].
+pub struct BatchedAnswer {
+    pub text: String,
+    pub details: GenerationDetails,
+}
+
+/// State of one sequence of [`generate_batch`].
+struct BatchedSequence {
+    logits_processor: LogitsProcessor,
+    generated_tokens: Vec<u32>,
+    text: String,
+    time_to_first_token: Option<Duration>,
+    finish_reason: Option<FinishReason>,
+}
+
+/// Answers `prompt` about every image, stepping all the sequences through the
+/// text model together. Every sequence starts from the same prompt, so they all
+/// have the same length and need no padding, which matters as candle's mixformer
+/// takes no attention mask. Finished sequences keep being fed end of text
+/// tokens until the last one is done. Nothing is streamed.
+pub fn generate_batch(
+    prompt: &str,
+    images: Vec<EncodedImage>,
+    moondream: &Moondream,
+    sampling: &SamplingConfig,
+    stopping: &StoppingConfig,
+    cancel: &CancelToken,
+) -> Result<Vec<BatchedAnswer>, Error> {
+    sampling.validate()?;
+    stopping.validate()?;
+    if images.is_empty() {
+        return Ok(vec![]);
+    }
+    let tokens = encode_prompt(prompt, &moondream.tokenizer)?;
+    let batch = images.len();
+    let device = &moondream.device;
+    let special_token = moondream.special_token;
+    let embeds: Vec<&Tensor> = images.iter().map(|image| &image.embeds).collect();
+    let embeds = Tensor::cat(&embeds, 0)?;
+    let mut sequences: Vec<BatchedSequence> = images
+        .iter()
+        .map(|_| BatchedSequence {
+            logits_processor: sampling.logits_processor(),
+            generated_tokens: vec![],
+            text: String::new(),
+            time_to_first_token: None,
+            finish_reason: None,
+        })
+        .collect();
+
+    let mut text_model = moondream.text_model();
+    let started_at = Instant::now();
+    let input = Tensor::new(tokens.as_slice(), device)?
+        .unsqueeze(0)?
+        .repeat((batch, 1))?;
+    let bos_token = Tensor::new(&[special_token], device)?
+        .unsqueeze(0)?
+        .repeat((batch, 1))?;
+    let mut logits = text_model.forward_with_img(&bos_token, &input, &embeds)?;
+    loop {
+        cancel.check()?;
+        let logits_f32 = logits.to_dtype(DType::F32)?;
+        let mut next_tokens = Vec::with_capacity(batch);
+        for (row, sequence) in sequences.iter_mut().enumerate() {
+            if sequence.finish_reason.is_some() {
+                next_tokens.push(special_token);
+                continue;
+            }
+            let logits = logits_f32.get(row)?;
+            let logits = if sampling.repeat_penalty == 1.0 {
+                logits
+            } else {
+                let generated = &sequence.generated_tokens;
+                let start_at = generated.len().saturating_sub(sampling.repeat_last_n);
+                candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    sampling.repeat_penalty,
+                    &generated[start_at..],
+                )?
+            };
+            let next_token = sequence.logits_processor.sample(&logits)?;
+            sequence
+                .time_to_first_token
+                .get_or_insert_with(|| started_at.elapsed());
+            sequence.generated_tokens.push(next_token);
+            next_tokens.push(next_token);
+
+            let decoded = moondream
+                .tokenizer
+                .decode(&sequence.generated_tokens, true)?;
+            sequence.finish_reason = if let Some(at) = find_stop(&stopping.stop, &decoded) {
+                sequence.text = decoded[..at].to_string();
+                Some(FinishReason::StopSequence)
+            } else if next_token == special_token {
+                sequence.text = decoded;
+                Some(FinishReason::Eos)
+            } else if sequence.generated_tokens.len() >= stopping.max_new_tokens {
+                sequence.text = decoded;
+                Some(FinishReason::Length)
+            } else {
+                None
+            };
+        }
+        if sequences
+            .iter()
+            .all(|sequence| sequence.finish_reason.is_some())
+        {
+            break;
+        }
+        let input = Tensor::new(next_tokens.as_slice(), device)?.unsqueeze(1)?;
+        logits = text_model.forward(&input)?;
+    }
+
+    let decode_time = started_at.elapsed().as_secs_f64();
+    Ok(sequences
+        .into_iter()
+        .zip(images)
+        .map(|(sequence, image)| BatchedAnswer {
+            text: sequence.text,
+            details: GenerationDetails {
+                finish_reason: sequence.finish_reason.unwrap_or(FinishReason::Length),
+                prompt_tokens: tokens.len(),
+                generated_tokens: sequence.generated_tokens.len(),
+                time_to_first_token_ms: sequence
+                    .time_to_first_token
+                    .map(|ttft| (image.encode_time + ttft).as_secs_f64() * 1000.0),
+                vision_encode_ms: image.encode_time.as_secs_f64() * 1000.0,
+                tokens_per_second: if decode_time > 0.0 {
+                    sequence.generated_tokens.len() as f64 / decode_time
+                } else {
+                    0.0
+                },
+            },
+        })
+        .collect())
+}
+
+/// Byte offset of the earliest of the `stop` sequences in `text`, if any.
+fn find_stop(stop: &[String], text: &str) -> Option<usize> {
+    stop.iter().filter_map(|stop| text.find(stop)).min()
+}
+
+/// One question and its answer within a [`Session`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+}
+
+/// A conversation about a single image. The session owns its own handle on the
+/// text model so its KV cache survives between questions; the weights are shared
+/// with the model it was created with, which it keeps alive even once another
+/// model, dtype or device has been selected.
+pub struct Session {
+    moondream: Arc<Moondream>,
+    text_model: TextModel,
+    image: EncodedImage,
+    /// Tokens sampled in the previous turn that have not been fed to the model yet.
+    pending: Vec<u32>,
+    /// Whether the image and earlier turns are already in the KV cache.
+    started: bool,
+    /// `pending` and `started` as they were before the current question, restored
+    /// if it is cancelled before reaching the model.
+    before_ask: (Vec<u32>, bool),
+    /// Shared so the history can be read while the session is answering.
+    turns: Arc<Mutex<Vec<Turn>>>,
+}
+
+/// What a [`Session`] needs to know about a finished pipeline.
+pub struct TurnOutcome {
+    pending: Vec<u32>,
+    answer: String,
+    /// Whether the question made it into the KV cache.
+    fed: bool,
+}
+
+impl Session {
+    pub fn new(
+        image: &ImageInput,
+        moondream: Arc<Moondream>,
+        embeddings: &Mutex<EmbeddingCache>,
+        cancel: &CancelToken,
+    ) -> Result<Self, Error> {
+        // A conversation is about a single view, the first frame or tile selected.
+        let image = encode_image(image, &moondream, embeddings, cancel)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::InputError("No frame selected".to_string()))?;
+        Ok(Self {
+            text_model: moondream.text_model(),
+            moondream,
+            image,
+            pending: vec![],
+            started: false,
+            before_ask: (vec![], false),
+            turns: Default::default(),
         })
     }
 
-    pub fn iter(&mut self) -> PipelineIter {
-        PipelineIter {
-            tokens: self.tokens.clone(),
-            image_embeds: self.image_embeds.clone(),
+    /// Builds a pipeline answering `prompt` as a follow-up to the earlier turns.
+    /// Call [`Session::finish_turn`] once it has been consumed.
+    pub fn ask(
+        &mut self,
+        prompt: &str,
+        sampling: &SamplingConfig,
+        stopping: &StoppingConfig,
+        cancel: &CancelToken,
+    ) -> Result<Pipeline<'_>, Error> {
+        let moondream = &*self.moondream;
+        // Everything that can fail happens before the session state changes, so
+        // a rejected question leaves the session as it was.
+        sampling.validate()?;
+        stopping.validate()?;
+        let prompt_tokens = encode_prompt(prompt, &moondream.tokenizer)?;
+        self.before_ask = (self.pending.clone(), self.started);
+        let mut tokens = std::mem::take(&mut self.pending);
+        tokens.extend(prompt_tokens);
+        let image = if self.started {
+            None
+        } else {
+            Some(self.image.clone())
+        };
+        self.started = true;
+        Pipeline::new(
+            &mut self.text_model,
+            &moondream.tokenizer,
+            &moondream.device,
+            moondream.special_token,
+            tokens,
+            image,
+            sampling,
+            stopping,
+            cancel,
+        )
+    }
+
+    /// Records the outcome of the pipeline returned by [`Session::ask`].
+    pub fn finish_turn(&mut self, question: String, outcome: TurnOutcome) {
+        if !outcome.fed {
+            (self.pending, self.started) = std::mem::take(&mut self.before_ask);
+            return;
+        }
+        self.pending = outcome.pending;
+        self.turns.lock().unwrap().push(Turn {
+            question,
+            answer: outcome.answer,
+        });
+    }
+
+    /// Forgets every turn so the next question starts again from the image alone.
+    pub fn reset(&mut self) {
+        self.text_model.clear_kv_cache();
+        self.pending.clear();
+        self.started = false;
+        self.turns.lock().unwrap().clear();
+    }
+
+    pub fn turns(&self) -> Arc<Mutex<Vec<Turn>>> {
+        self.turns.clone()
+    }
+}
+
+pub struct PipelineIter<'a, 'm> {
+    pipeline: &'a mut Pipeline<'m>,
+}
+
+pub struct Pipeline<'m> {
+    text_model: &'m mut TextModel,
+    tokenizer: &'m Tokenizer,
+    device: Device,
+    special_token: u32,
+    logits_processor: LogitsProcessor,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    /// Tokens to feed on the next step.
+    tokens: Vec<u32>,
+    /// Present until the image has been fed alongside the first prompt.
+    image_embeds: Option<Tensor>,
+    prompt_tokens: usize,
+    vision_encode_time: Duration,
+    started_at: Instant,
+    time_to_first_token: Option<Duration>,
+    generated_tokens: Vec<u32>,
+    max_new_tokens: usize,
+    stop: Vec<String>,
+    /// Bytes of the decoded answer already handed out in a [`Token`].
+    emitted: usize,
+    /// Final answer, set once the generation has finished.
+    finished_text: Option<String>,
+    /// Whether the prompt has been fed to the model.
+    fed: bool,
+    cancel: CancelToken,
+    part: Option<ImagePart>,
+}
+
+impl<'m> Pipeline<'m> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        text_model: &'m mut TextModel,
+        tokenizer: &'m Tokenizer,
+        device: &Device,
+        special_token: u32,
+        tokens: Vec<u32>,
+        image: Option<EncodedImage>,
+        sampling: &SamplingConfig,
+        stopping: &StoppingConfig,
+        cancel: &CancelToken,
+    ) -> Result<Self, Error> {
+        sampling.validate()?;
+        stopping.validate()?;
+        let logits_processor = sampling.logits_processor();
+        Ok(Self {
+            text_model,
+            tokenizer,
+            device: device.clone(),
+            special_token,
+            logits_processor,
+            repeat_penalty: sampling.repeat_penalty,
+            repeat_last_n: sampling.repeat_last_n,
+            prompt_tokens: tokens.len(),
+            tokens,
+            vision_encode_time: image
+                .as_ref()
+                .map(|image| image.encode_time)
+                .unwrap_or_default(),
+            image_embeds: image.map(|image| image.embeds),
+            started_at: Instant::now(),
+            time_to_first_token: None,
             generated_tokens: vec![],
-            pipeline: self,
-            i: 0,
-            last: false,
+            max_new_tokens: stopping.max_new_tokens,
+            stop: stopping.stop.clone(),
+            emitted: 0,
+            finished_text: None,
+            fed: false,
+            cancel: cancel.clone(),
+            part: None,
+        })
+    }
+
+    /// Tags the generations with the part of the image they answer about.
+    pub fn set_part(&mut self, part: ImagePart) {
+        self.part = Some(part);
+    }
+
+    pub fn iter(&mut self) -> PipelineIter<'_, 'm> {
+        PipelineIter { pipeline: self }
+    }
+
+    /// Hands what a session needs to continue after this pipeline.
+    pub fn outcome(&self) -> Result<TurnOutcome, Error> {
+        Ok(TurnOutcome {
+            pending: self.tokens.clone(),
+            answer: self.generated_text()?,
+            fed: self.fed,
+        })
+    }
+
+    pub fn generated_text(&self) -> Result<String, Error> {
+        match &self.finished_text {
+            Some(text) => Ok(text.clone()),
+            None => Ok(self.tokenizer.decode(&self.generated_tokens, true)?),
+        }
+    }
+
+    fn details(&self, finish_reason: FinishReason) -> GenerationDetails {
+        let decode_time = self.started_at.elapsed().as_secs_f64();
+        let tokens_per_second = if decode_time > 0.0 {
+            self.generated_tokens.len() as f64 / decode_time
+        } else {
+            0.0
+        };
+        GenerationDetails {
+            finish_reason,
+            prompt_tokens: self.prompt_tokens,
+            generated_tokens: self.generated_tokens.len(),
+            time_to_first_token_ms: self
+                .time_to_first_token
+                .map(|ttft| (self.vision_encode_time + ttft).as_secs_f64() * 1000.0),
+            vision_encode_ms: self.vision_encode_time.as_secs_f64() * 1000.0,
+            tokens_per_second,
         }
     }
+
+    /// Final event for a generation that ended before the iterator did, because it
+    /// was cancelled or failed. The answer is cut where the last emitted token ended.
+    pub fn interrupted(&mut self, finish_reason: FinishReason) -> Result<Generation, Error> {
+        let decoded = self.tokenizer.decode(&self.generated_tokens, true)?;
+        let generated_text = decoded.get(..self.emitted).unwrap_or(&decoded).to_string();
+        self.finished_text = Some(generated_text.clone());
+        Ok(Generation {
+            token: Token {
+                id: self.special_token as usize,
+                text: String::new(),
+                special: true,
+            },
+            generated_text: Some(generated_text),
+            details: Some(self.details(finish_reason)),
+            part: self.part.clone(),
+        })
+    }
+}
+
+/// Length of the longest suffix of `text` that could still grow into one of the
+/// `stop` sequences once more tokens are decoded.
+fn partial_stop_len(stop: &[String], text: &str) -> usize {
+    stop.iter()
+        .flat_map(|stop| {
+            (1..stop.len())
+                .rev()
+                .filter(|&n| stop.is_char_boundary(n) && text.ends_with(&stop[..n]))
+                .take(1)
+        })
+        .max()
+        .unwrap_or(0)
 }
 
-impl<'a> PipelineIter<'a> {
+/// Length of the end of `text` that may still change once more tokens are
+/// decoded, and is held back while streaming: the start of a stop sequence, or a
+/// character split across tokens, which decodes as U+FFFD until it is complete.
+fn unstable_suffix_len(stop: &[String], text: &str) -> usize {
+    let incomplete = text.len() - text.trim_end_matches(char::REPLACEMENT_CHARACTER).len();
+    partial_stop_len(stop, text).max(incomplete)
+}
+
+impl<'a, 'm> PipelineIter<'a, 'm> {
     fn inner_next(&mut self) -> Result<Generation, Error> {
-        let special_token = self.pipeline.special_token;
-        let input = Tensor::new(self.tokens.as_slice(), &self.pipeline.device)?.unsqueeze(0)?;
-        let logits = if self.i > 0 {
-            self.pipeline.model.text_model.forward(&input)?
-        } else {
-            let bos_token = Tensor::new(&[special_token], &self.pipeline.device)?.unsqueeze(0)?;
-            let logits = self.pipeline.model.text_model.forward_with_img(
-                &bos_token,
-                &input,
-                &self.image_embeds,
-            )?;
+        let pipeline = &mut *self.pipeline;
+        let special_token = pipeline.special_token;
+        let logits = match pipeline.image_embeds.take() {
+            Some(image_embeds) => {
+                let input =
+                    Tensor::new(pipeline.tokens.as_slice(), &pipeline.device)?.unsqueeze(0)?;
+                let bos_token = Tensor::new(&[special_token], &pipeline.device)?.unsqueeze(0)?;
+                pipeline
+                    .text_model
+                    .forward_with_img(&bos_token, &input, &image_embeds)?
+            }
+            // Candle's mixformer builds its causal mask without the length already in
+            // the KV cache, so a follow-up prompt is fed one token at a time.
+            None => {
+                let mut logits = None;
+                for &token in &pipeline.tokens {
+                    let input = Tensor::new(&[token], &pipeline.device)?.unsqueeze(0)?;
+                    logits = Some(pipeline.text_model.forward(&input)?);
+                }
+                logits.ok_or_else(|| Error::InputError("Empty prompt".to_string()))?
+            }
+        };
+        pipeline.fed = true;
+        let logits = logits.squeeze(0)?.to_dtype(DType::F32)?;
+        let logits = if pipeline.repeat_penalty == 1.0 {
             logits
+        } else {
+            let generated = &pipeline.generated_tokens;
+            let start_at = generated.len().saturating_sub(pipeline.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                pipeline.repeat_penalty,
+                &generated[start_at..],
+            )?
         };
-        let logits = logits.squeeze(0)?.to_dtype(DType::F16)?;
-        let next_token = self.pipeline.logits_processor.sample(&logits)?;
-        let text = self.pipeline.tokenizer.decode(&[next_token], true)?;
-        tracing::debug!("Generated token: {}", text);
-        self.generated_tokens.push(next_token);
-        self.tokens = vec![next_token];
-        let stop = next_token == special_token;
-        let generated_text = if stop {
+        let next_token = pipeline.logits_processor.sample(&logits)?;
+        if pipeline.time_to_first_token.is_none() {
+            pipeline.time_to_first_token = Some(pipeline.started_at.elapsed());
+        }
+        pipeline.generated_tokens.push(next_token);
+        let eos = next_token == special_token;
+        // The end of text token is never fed back, so a follow-up turn continues right
+        // after the answer.
+        pipeline.tokens = if eos { vec![] } else { vec![next_token] };
+
+        // Decode the whole answer so multi-token characters and stop sequences spanning
+        // several tokens are seen as a whole.
+        let decoded = pipeline
+            .tokenizer
+            .decode(&pipeline.generated_tokens, true)?;
+        let (end, finish_reason) = if let Some(at) = find_stop(&pipeline.stop, &decoded) {
+            tracing::debug!("Stop sequence found. Stopping...");
+            // Whatever followed the stop sequence should not reach a follow-up turn.
+            pipeline.tokens.clear();
+            (at, Some(FinishReason::StopSequence))
+        } else if eos {
             tracing::debug!("End of text. Stopping...");
-            Some(
-                self.pipeline
-                    .tokenizer
-                    .decode(&self.generated_tokens, true)?,
+            (decoded.len(), Some(FinishReason::Eos))
+        } else if pipeline.generated_tokens.len() >= pipeline.max_new_tokens {
+            tracing::debug!(
+                "Reached {} new tokens. Stopping...",
+                pipeline.max_new_tokens
+            );
+            (decoded.len(), Some(FinishReason::Length))
+        } else {
+            (
+                decoded.len() - unstable_suffix_len(&pipeline.stop, &decoded),
+                None,
             )
+        };
+        let end = end.max(pipeline.emitted);
+        let text = decoded
+            .get(pipeline.emitted..end)
+            .unwrap_or_default()
+            .to_string();
+        tracing::debug!("Generated token: {}", text);
+        pipeline.emitted = end;
+        let generated_text = if finish_reason.is_some() {
+            let generated_text = decoded.get(..end).unwrap_or(&decoded).to_string();
+            pipeline.finished_text = Some(generated_text.clone());
+            Some(generated_text)
         } else {
             None
         };
-        self.i += 1;
         Ok(Generation {
             token: Token {
                 id: next_token as usize,
                 text,
-                special: stop,
+                special: eos,
             },
             generated_text,
-            details: None,
+            details: finish_reason.map(|reason| pipeline.details(reason)),
+            part: pipeline.part.clone(),
         })
     }
 }
 
-impl<'a> Iterator for PipelineIter<'a> {
+impl<'a, 'm> Iterator for PipelineIter<'a, 'm> {
     type Item = Result<Generation, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.last {
+        if self.pipeline.finished_text.is_some() {
             return None;
         }
-        let generation = self.inner_next();
-        if let Ok(generation) = &generation {
-            if generation.generated_text.is_some() {
-                self.last = true;
+        if self.pipeline.cancel.is_cancelled() {
+            tracing::debug!("Cancelled. Stopping...");
+            return Some(self.pipeline.interrupted(FinishReason::Cancelled));
+        }
+        Some(self.inner_next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{base64img::TEST_IMG, utils::ImageSource};
+    use candle_nn::VarMap;
+    use std::str::FromStr;
+
+    const TOKENIZER: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [{
+            "id": 0,
+            "content": "<|endoftext|>",
+            "single_word": false,
+            "lstrip": false,
+            "rstrip": false,
+            "normalized": false,
+            "special": true
+        }],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {
+            "type": "WordLevel",
+            "vocab": {"<|endoftext|>": 0, "<unk>": 1, "Question": 2, ":": 3, "Answer": 4},
+            "unk_token": "<unk>"
+        }
+    }"#;
+
+    /// Moondream with a single text layer, a single vision block and every
+    /// weight zero, so pipelines run without any model files. It always
+    /// answers with an end of text token.
+    fn tiny_moondream() -> Moondream {
+        tiny_moondream_with(VarBuilder::zeros(DType::F32, &Device::Cpu))
+    }
+
+    fn tiny_moondream_with(vb: VarBuilder) -> Moondream {
+        let config: moondream::Config = serde_json::from_value(serde_json::json!({
+            "phi_config": {
+                "vocab_size": 16,
+                "n_positions": 2048,
+                "n_embd": 8,
+                "n_layer": 1,
+                "n_inner": null,
+                "n_head": 2,
+                "rotary_dim": 4,
+                "activation_function": "gelu",
+                "layer_norm_epsilon": 1e-5,
+                "tie_word_embeddings": false,
+                "pad_vocab_size_multiple": 1
+            },
+            "vision_config": {
+                "image_embedding_dim": 1152,
+                "model_dim": 8,
+                "hidden_dim": 16,
+                "hidden_features": 16,
+                "embed_len": 729,
+                "embed_dim": 1152,
+                "num_blocks": 1,
+                "num_heads": 2,
+                "act": "gelu"
             }
+        }))
+        .unwrap();
+        let device = Device::Cpu;
+        let tokenizer = Tokenizer::from_str(TOKENIZER).unwrap();
+        Moondream {
+            model: Model::Full(moondream::Model::new(&config, vb).unwrap()),
+            special_token: tokenizer.token_to_id("<|endoftext|>").unwrap(),
+            tokenizer,
+            device,
+            dtype: DType::F32,
+            preprocess: RwLock::new(ConfigVariant::V2.preprocess(PreprocessSettings::default())),
+            fingerprint: ModelDescriptor::default().fingerprint(),
+        }
+    }
+
+    fn test_image() -> ImageInput {
+        ImageInput {
+            source: ImageSource::Base64 {
+                data: TEST_IMG.clone(),
+            },
+            frames: Default::default(),
+            crop: None,
+            tiling: None,
+        }
+    }
+
+    /// Text, finish reason and token count of the answer of a single sequence
+    /// pipeline.
+    fn single_answer(
+        moondream: &Moondream,
+        image: EncodedImage,
+        sampling: &SamplingConfig,
+        stopping: &StoppingConfig,
+    ) -> (String, FinishReason, usize) {
+        let mut text_model = moondream.text_model();
+        let cancel = CancelToken::default();
+        let mut pipeline = build_pipeline(
+            "What is this?",
+            image,
+            moondream,
+            &mut text_model,
+            sampling,
+            stopping,
+            &cancel,
+        )
+        .unwrap();
+        let mut details = None;
+        for generation in pipeline.iter() {
+            if let Some(last) = generation.unwrap().details() {
+                details = Some(last.clone());
+            }
+        }
+        let details = details.expect("the last generation has details");
+        (
+            pipeline.generated_text().unwrap(),
+            details.finish_reason,
+            details.generated_tokens,
+        )
+    }
+
+    fn stops(stops: &[&str]) -> Vec<String> {
+        stops.iter().map(|stop| stop.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_the_earliest_stop_sequence() {
+        let stop = stops(&["END", "\n\n"]);
+        assert_eq!(find_stop(&stop, "a cat\n\nEND"), Some(5));
+        assert_eq!(find_stop(&stop, "a catEND\n\n"), Some(5));
+        assert_eq!(find_stop(&stop, "a cat"), None);
+        assert_eq!(find_stop(&[], "a cat"), None);
+    }
+
+    #[test]
+    fn holds_back_the_start_of_a_stop_sequence() {
+        let stop = stops(&["END", "Question:"]);
+        assert_eq!(partial_stop_len(&stop, "a cat EN"), 2);
+        assert_eq!(partial_stop_len(&stop, "a cat Quest"), 5);
+        assert_eq!(partial_stop_len(&stop, "a cat"), 0);
+        // A complete stop sequence is found by `find_stop`, not held back.
+        assert_eq!(partial_stop_len(&stop, "a cat END"), 0);
+    }
+
+    #[test]
+    fn partial_stop_ends_on_a_char_boundary() {
+        let stop = stops(&["é!"]);
+        assert_eq!(partial_stop_len(&stop, "café"), "é".len());
+        assert_eq!(partial_stop_len(&stop, "cafe"), 0);
+    }
+
+    #[test]
+    fn holds_back_incomplete_characters() {
+        assert_eq!(unstable_suffix_len(&[], "a cat \u{FFFD}"), 3);
+        assert_eq!(unstable_suffix_len(&[], "a cat \u{FFFD}\u{FFFD}"), 6);
+        assert_eq!(unstable_suffix_len(&[], "a cat"), 0);
+        assert_eq!(unstable_suffix_len(&stops(&["cat!"]), "a cat"), 3);
+    }
+
+    #[test]
+    fn session_answers_follow_up_questions() {
+        let moondream = Arc::new(tiny_moondream());
+        let embeddings = Mutex::new(EmbeddingCache::new(1, None, 0));
+        let cancel = CancelToken::default();
+        let mut session = Session::new(&test_image(), moondream, &embeddings, &cancel).unwrap();
+        // The second question reaches the model with the first turn in the KV cache.
+        for question in ["What is this?", "And the colour?"] {
+            let mut pipeline = session
+                .ask(
+                    question,
+                    &SamplingConfig::default(),
+                    &StoppingConfig::default(),
+                    &cancel,
+                )
+                .unwrap();
+            for generation in pipeline.iter() {
+                generation.unwrap();
+            }
+            let outcome = pipeline.outcome().unwrap();
+            session.finish_turn(question.to_string(), outcome);
+        }
+        assert_eq!(session.turns().lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejected_question_leaves_the_session_untouched() {
+        let moondream = Arc::new(tiny_moondream());
+        let embeddings = Mutex::new(EmbeddingCache::new(1, None, 0));
+        let cancel = CancelToken::default();
+        let mut session = Session::new(&test_image(), moondream, &embeddings, &cancel).unwrap();
+        let invalid = SamplingConfig {
+            temperature: Some(-1.0),
+            ..Default::default()
+        };
+        let result = session.ask(
+            "What is this?",
+            &invalid,
+            &StoppingConfig::default(),
+            &cancel,
+        );
+        assert!(result.is_err());
+        assert!(!session.started);
+        // The next question still feeds the image.
+        let pipeline = session
+            .ask(
+                "What is this?",
+                &SamplingConfig::default(),
+                &StoppingConfig::default(),
+                &cancel,
+            )
+            .unwrap();
+        assert!(pipeline.image_embeds.is_some());
+    }
+
+    #[test]
+    fn batched_answers_match_single_pipelines() {
+        // Random weights, so the rows answer differently and finish apart.
+        let varmap = VarMap::new();
+        let moondream =
+            tiny_moondream_with(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu));
+        let sampling = SamplingConfig {
+            repeat_penalty: 1.5,
+            ..Default::default()
+        };
+        let stopping = StoppingConfig {
+            max_new_tokens: 12,
+            ..Default::default()
+        };
+        let images: Vec<EncodedImage> = (0..3)
+            .map(|_| EncodedImage {
+                embeds: Tensor::randn(0f32, 1f32, (1, 729, 8), &Device::Cpu).unwrap(),
+                encode_time: Duration::ZERO,
+                frame: 0,
+                tile: None,
+            })
+            .collect();
+        let expected: Vec<_> = images
+            .iter()
+            .map(|image| single_answer(&moondream, image.clone(), &sampling, &stopping))
+            .collect();
+        let cancel = CancelToken::default();
+        for batch_size in [1, 3] {
+            let answers: Vec<_> = images
+                .chunks(batch_size)
+                .flat_map(|chunk| {
+                    generate_batch(
+                        "What is this?",
+                        chunk.to_vec(),
+                        &moondream,
+                        &sampling,
+                        &stopping,
+                        &cancel,
+                    )
+                    .unwrap()
+                })
+                .map(|answer| {
+                    (
+                        answer.text,
+                        answer.details.finish_reason,
+                        answer.details.generated_tokens,
+                    )
+                })
+                .collect();
+            assert_eq!(answers, expected, "batch size {batch_size}");
         }
-        Some(generation)
     }
 }
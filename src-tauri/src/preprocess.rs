@@ -0,0 +1,709 @@
+use std::io::Cursor;
+
+use candle::{DType, Device, Tensor};
+use image::{
+    codecs::{gif::GifDecoder, webp::WebPDecoder},
+    imageops::FilterType,
+    AnimationDecoder, DynamicImage, Frames, GrayAlphaImage, GrayImage, ImageDecoder, ImageFormat,
+    ImageReader, Rgb, RgbImage, RgbaImage,
+};
+use serde::{Deserialize, Serialize};
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult};
+use tiff::ColorType as TiffColorType;
+
+use crate::{embeddings::content_hash, Error};
+
+/// How a picture is brought to the square input of the vision encoder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeStrategy {
+    /// Scale to cover the square and crop what overflows.
+    Fill,
+    /// Scale to fit inside the square and pad the rest, so no edge is lost.
+    #[default]
+    Letterbox,
+    /// Scale each side independently, distorting the aspect ratio.
+    Stretch,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Nearest,
+    #[default]
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<Filter> for FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Gaussian => FilterType::Gaussian,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// The user facing part of the preprocessing, persisted in the settings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PreprocessSettings {
+    pub resize: ResizeStrategy,
+    pub filter: Filter,
+}
+
+/// Everything needed to turn an encoded image into the vision encoder input.
+/// `size`, `mean` and `std` come from the model config.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PreprocessConfig {
+    pub size: u32,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    #[serde(flatten)]
+    pub settings: PreprocessSettings,
+}
+
+impl Default for PreprocessConfig {
+    /// Moondream 2 input: 378x378, normalized to [-1, 1].
+    fn default() -> Self {
+        Self {
+            size: 378,
+            mean: [0.5, 0.5, 0.5],
+            std: [0.5, 0.5, 0.5],
+            settings: PreprocessSettings::default(),
+        }
+    }
+}
+
+impl PreprocessConfig {
+    /// Short digest of the config, so embeddings computed with another
+    /// preprocessing are not reused.
+    pub fn fingerprint(&self) -> String {
+        let config = serde_json::to_vec(self).unwrap_or_default();
+        content_hash(&config)[..8].to_string()
+    }
+
+    /// Decodes an encoded image (png, jpeg...) in memory and returns a tensor with
+    /// shape (3, size, size). Only the first frame of animations is used.
+    pub fn apply(&self, bytes: &[u8]) -> candle::Result<Tensor> {
+        let frames =
+            decode_frames(bytes, FrameSelection::default()).map_err(candle::Error::wrap)?;
+        self.to_tensor(&frames[0].image)
+    }
+
+    /// Resizes and normalizes a decoded image into a tensor with shape
+    /// (3, size, size).
+    pub fn to_tensor(&self, img: &DynamicImage) -> candle::Result<Tensor> {
+        let img = self.resize(img);
+        let size = self.size as usize;
+        let data = img.into_raw();
+        let data = Tensor::from_vec(data, (size, size, 3), &Device::Cpu)?.permute((2, 0, 1))?;
+        let mean = Tensor::new(&self.mean, &Device::Cpu)?.reshape((3, 1, 1))?;
+        let std = Tensor::new(&self.std, &Device::Cpu)?.reshape((3, 1, 1))?;
+        (data.to_dtype(DType::F32)? / 255.)?
+            .broadcast_sub(&mean)?
+            .broadcast_div(&std)
+    }
+
+    fn resize(&self, img: &DynamicImage) -> RgbImage {
+        let size = self.size;
+        let filter = self.settings.filter.into();
+        match self.settings.resize {
+            ResizeStrategy::Fill => img.resize_to_fill(size, size, filter).to_rgb8(),
+            ResizeStrategy::Stretch => img.resize_exact(size, size, filter).to_rgb8(),
+            ResizeStrategy::Letterbox => {
+                let img = img.resize(size, size, filter).to_rgb8();
+                // Padding with the mean color makes it zero once normalized.
+                let pad = Rgb(self.mean.map(|m| (m * 255.0).round() as u8));
+                let mut canvas = RgbImage::from_pixel(size, size, pad);
+                let x = (size - img.width()) / 2;
+                let y = (size - img.height()) / 2;
+                image::imageops::replace(&mut canvas, &img, x.into(), y.into());
+                canvas
+            }
+        }
+    }
+}
+
+/// Which frames of an animated or multi-page image to look at. Still images
+/// only have frame 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum FrameSelection {
+    Frame {
+        index: usize,
+    },
+    /// `count` frames evenly spread over the animation, starting at the first.
+    Sample {
+        count: usize,
+    },
+}
+
+impl Default for FrameSelection {
+    fn default() -> Self {
+        FrameSelection::Frame { index: 0 }
+    }
+}
+
+impl FrameSelection {
+    fn indices(&self, frame_count: usize) -> Result<Vec<usize>, Error> {
+        match *self {
+            FrameSelection::Frame { index } if index < frame_count => Ok(vec![index]),
+            FrameSelection::Frame { index } => Err(Error::InputError(format!(
+                "Frame {index} does not exist, the image has {frame_count} frames"
+            ))),
+            FrameSelection::Sample { count: 0 } => Err(Error::InputError(
+                "Frame count must be positive".to_string(),
+            )),
+            FrameSelection::Sample { count } => {
+                let mut indices: Vec<usize> = (0..count.min(frame_count))
+                    .map(|i| i * frame_count / count.min(frame_count))
+                    .collect();
+                indices.dedup();
+                Ok(indices)
+            }
+        }
+    }
+}
+
+/// A frame picked out of the input, with its position in the animation.
+pub struct DecodedFrame {
+    pub index: usize,
+    pub image: DynamicImage,
+}
+
+/// Decodes the selected frames of `bytes`. GIF, animated WebP and multi-page
+/// TIFF have several frames, every other format a single one.
+pub fn decode_frames(bytes: &[u8], selection: FrameSelection) -> Result<Vec<DecodedFrame>, Error> {
+    let format = image::guess_format(bytes).map_err(|_| unsupported_format(bytes))?;
+    let single = || select_frames(std::iter::once(decode(bytes)), || Ok(1), selection);
+    match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(Cursor::new(bytes))?;
+            select_frames(
+                animation_frames(decoder.into_frames()),
+                || {
+                    count_frames(animation_frames(
+                        GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+                    ))
+                },
+                selection,
+            )
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+            if !decoder.has_animation() {
+                return single();
+            }
+            select_frames(
+                animation_frames(decoder.into_frames()),
+                || {
+                    count_frames(animation_frames(
+                        WebPDecoder::new(Cursor::new(bytes))?.into_frames(),
+                    ))
+                },
+                selection,
+            )
+        }
+        ImageFormat::Tiff => {
+            let (page_count, supported) = tiff_layout(bytes)?;
+            if page_count == 1 || !supported {
+                if page_count > 1 {
+                    tracing::debug!("Only reading the first of {} TIFF pages", page_count);
+                }
+                // The image crate reads more layouts, but only the first page.
+                return single();
+            }
+            select_frames(TiffPages::new(bytes)?, || Ok(page_count), selection)
+        }
+        _ => single(),
+    }
+}
+
+/// Keeps the selected frames. Frames are decoded as they are pulled and only
+/// the selected ones are kept; decoding stops after the last of them.
+/// `frame_count` is only called to spread a sample over the whole animation.
+fn select_frames<I>(
+    frames: I,
+    frame_count: impl FnOnce() -> Result<usize, Error>,
+    selection: FrameSelection,
+) -> Result<Vec<DecodedFrame>, Error>
+where
+    I: Iterator<Item = Result<DynamicImage, Error>>,
+{
+    let indices = match selection {
+        FrameSelection::Frame { index } => vec![index],
+        FrameSelection::Sample { .. } => match frame_count()? {
+            0 => return Err(Error::InputError("Image has no frames".to_string())),
+            frame_count => selection.indices(frame_count)?,
+        },
+    };
+    let last = indices.last().copied().unwrap_or_default();
+    let mut selected = vec![];
+    let mut frame_count = 0;
+    for (index, frame) in frames.take(last + 1).enumerate() {
+        let image = frame?;
+        frame_count = index + 1;
+        if indices.contains(&index) {
+            selected.push(DecodedFrame { index, image });
+        }
+    }
+    if frame_count == 0 {
+        return Err(Error::InputError("Image has no frames".to_string()));
+    }
+    if selected.len() < indices.len() {
+        // Only a single frame past the end can be missing, `indices` reports it.
+        selection.indices(frame_count)?;
+    }
+    Ok(selected)
+}
+
+/// A rectangle in image pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    fn check(&self, width: u32, height: u32) -> Result<(), Error> {
+        let inside = self.width > 0
+            && self.height > 0
+            && self
+                .x
+                .checked_add(self.width)
+                .is_some_and(|right| right <= width)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|bottom| bottom <= height);
+        if inside {
+            Ok(())
+        } else {
+            Err(Error::InputError(format!(
+                "Crop {self:?} is not inside the {width}x{height} image"
+            )))
+        }
+    }
+}
+
+/// Splits large images into overlapping tiles encoded separately, so details
+/// survive the downscaling to the encoder input size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TilingConfig {
+    /// Side of a tile in image pixels. Images that fit are not split.
+    pub tile_size: u32,
+    /// Pixels shared by neighbouring tiles, so text on a seam is seen whole.
+    pub overlap: u32,
+    /// Tiles encoded for a request, over all the selected frames.
+    pub max_tiles: usize,
+    /// Answer once from all the tiles instead of streaming an answer per tile.
+    pub aggregate: bool,
+}
+
+impl Default for TilingConfig {
+    fn default() -> Self {
+        Self {
+            tile_size: 756,
+            overlap: 64,
+            max_tiles: 16,
+            aggregate: false,
+        }
+    }
+}
+
+impl TilingConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.tile_size == 0 || self.overlap >= self.tile_size {
+            return Err(Error::InputError(
+                "Tile overlap must be smaller than the tile size".to_string(),
+            ));
+        }
+        if self.max_tiles == 0 {
+            return Err(Error::InputError("Max tiles must be positive".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Start offsets of the tiles along a side of `len` pixels. The last tile is
+    /// moved back to end on the border rather than hang over it.
+    fn offsets(&self, len: u32) -> Vec<u32> {
+        if len <= self.tile_size {
+            return vec![0];
+        }
+        let step = self.tile_size - self.overlap;
+        let count = (len - self.overlap).div_ceil(step);
+        (0..count)
+            .map(|i| (i * step).min(len - self.tile_size))
+            .collect()
+    }
+
+    fn regions(&self, width: u32, height: u32) -> Result<Vec<Region>, Error> {
+        let xs = self.offsets(width);
+        let ys = self.offsets(height);
+        if xs.len() * ys.len() > self.max_tiles {
+            return Err(Error::InputError(format!(
+                "A {width}x{height} image needs {} tiles, more than {}",
+                xs.len() * ys.len(),
+                self.max_tiles
+            )));
+        }
+        Ok(ys
+            .iter()
+            .flat_map(|&y| {
+                xs.iter().map(move |&x| Region {
+                    x,
+                    y,
+                    width: self.tile_size.min(width),
+                    height: self.tile_size.min(height),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Part of a frame sent to the vision encoder.
+pub struct ImageView {
+    pub frame: usize,
+    /// Tile in the coordinates of the full frame, `None` when not tiled.
+    pub tile: Option<Region>,
+    pub image: DynamicImage,
+}
+
+/// Crops every frame to `crop` and splits it into tiles if `tiling` is set.
+pub fn views(
+    frames: Vec<DecodedFrame>,
+    crop: Option<Region>,
+    tiling: Option<TilingConfig>,
+) -> Result<Vec<ImageView>, Error> {
+    let mut views = vec![];
+    for frame in frames {
+        let (image, origin) = match crop {
+            Some(crop) => {
+                crop.check(frame.image.width(), frame.image.height())?;
+                let image = frame
+                    .image
+                    .crop_imm(crop.x, crop.y, crop.width, crop.height);
+                (image, (crop.x, crop.y))
+            }
+            None => (frame.image, (0, 0)),
+        };
+        let Some(tiling) = tiling else {
+            views.push(ImageView {
+                frame: frame.index,
+                tile: None,
+                image,
+            });
+            continue;
+        };
+        tiling.validate()?;
+        let regions = tiling.regions(image.width(), image.height())?;
+        // Each tile is encoded and answered, sampled frames multiply them.
+        if views.len() + regions.len() > tiling.max_tiles {
+            return Err(Error::InputError(format!(
+                "The selected frames need more than {} tiles",
+                tiling.max_tiles
+            )));
+        }
+        for region in regions {
+            views.push(ImageView {
+                frame: frame.index,
+                tile: Some(Region {
+                    x: origin.0 + region.x,
+                    y: origin.1 + region.y,
+                    ..region
+                }),
+                image: image.crop_imm(region.x, region.y, region.width, region.height),
+            });
+        }
+    }
+    Ok(views)
+}
+
+fn unsupported_format(bytes: &[u8]) -> Error {
+    // HEIF files start with an `ftyp` box naming their brand.
+    let heif = bytes.get(4..8) == Some(&b"ftyp"[..])
+        && matches!(
+            bytes.get(8..12),
+            Some(b"heic" | b"heix" | b"mif1" | b"msf1")
+        );
+    if heif {
+        Error::InputError("HEIC images are not supported, convert them to JPEG".to_string())
+    } else {
+        Error::InputError("Unsupported image format".to_string())
+    }
+}
+
+fn animation_frames(frames: Frames<'_>) -> impl Iterator<Item = Result<DynamicImage, Error>> + '_ {
+    frames.map(|frame| {
+        frame
+            .map(|frame| DynamicImage::ImageRgba8(frame.into_buffer()))
+            .map_err(Error::from)
+    })
+}
+
+fn count_frames(frames: impl Iterator<Item = Result<DynamicImage, Error>>) -> Result<usize, Error> {
+    frames.try_fold(0, |count, frame| frame.map(|_| count + 1))
+}
+
+/// Page count of a TIFF, and whether [`TiffPages`] can read every page.
+fn tiff_layout(bytes: &[u8]) -> Result<(usize, bool), Error> {
+    let mut decoder = TiffDecoder::new(Cursor::new(bytes)).map_err(tiff_error)?;
+    let mut page_count = 0;
+    let mut supported = true;
+    loop {
+        page_count += 1;
+        let color_type = decoder.colortype().map_err(tiff_error)?;
+        supported &= matches!(
+            color_type,
+            TiffColorType::Gray(8)
+                | TiffColorType::GrayA(8)
+                | TiffColorType::RGB(8)
+                | TiffColorType::RGBA(8)
+        );
+        if !decoder.more_images() {
+            return Ok((page_count, supported));
+        }
+        decoder.next_image().map_err(tiff_error)?;
+    }
+}
+
+/// Pages of a multi-page TIFF, read one at a time. The image crate only reads
+/// the first page, so pages are read with the tiff crate. Only 8 bit gray and
+/// RGB(A) pages are supported.
+struct TiffPages<'a> {
+    decoder: TiffDecoder<Cursor<&'a [u8]>>,
+    started: bool,
+    done: bool,
+}
+
+impl<'a> TiffPages<'a> {
+    fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        Ok(Self {
+            decoder: TiffDecoder::new(Cursor::new(bytes)).map_err(tiff_error)?,
+            started: false,
+            done: false,
+        })
+    }
+
+    fn read_page(&mut self) -> Result<DynamicImage, Error> {
+        let (width, height) = self.decoder.dimensions().map_err(tiff_error)?;
+        let color_type = self.decoder.colortype().map_err(tiff_error)?;
+        let unsupported =
+            || Error::InputError(format!("Unsupported TIFF color type {color_type:?}"));
+        let DecodingResult::U8(data) = self.decoder.read_image().map_err(tiff_error)? else {
+            return Err(unsupported());
+        };
+        let page = match color_type {
+            TiffColorType::Gray(8) => GrayImage::from_raw(width, height, data).map(Into::into),
+            TiffColorType::GrayA(8) => {
+                GrayAlphaImage::from_raw(width, height, data).map(Into::into)
+            }
+            TiffColorType::RGB(8) => RgbImage::from_raw(width, height, data).map(Into::into),
+            TiffColorType::RGBA(8) => RgbaImage::from_raw(width, height, data).map(Into::into),
+            _ => None,
+        };
+        page.ok_or_else(unsupported)
+    }
+}
+
+impl Iterator for TiffPages<'_> {
+    type Item = Result<DynamicImage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.started {
+            if !self.decoder.more_images() {
+                self.done = true;
+                return None;
+            }
+            if let Err(e) = self.decoder.next_image() {
+                self.done = true;
+                return Some(Err(tiff_error(e)));
+            }
+        }
+        self.started = true;
+        let page = self.read_page();
+        self.done = page.is_err();
+        Some(page)
+    }
+}
+
+fn tiff_error(e: tiff::TiffError) -> Error {
+    Error::InputError(format!("Invalid TIFF image: {e}"))
+}
+
+/// Decodes `bytes`, turning the picture upright according to its EXIF
+/// orientation so phone photos are not seen sideways.
+fn decode(bytes: &[u8]) -> Result<DynamicImage, Error> {
+    let mut decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_decoder()
+        .map_err(|e| match e {
+            image::ImageError::Unsupported(_) => unsupported_format(bytes),
+            e => e.into(),
+        })?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn tiling(tile_size: u32, overlap: u32) -> TilingConfig {
+        TilingConfig {
+            tile_size,
+            overlap,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tiles_end_on_the_border_of_uneven_sizes() {
+        let tiling = tiling(100, 20);
+        assert_eq!(tiling.offsets(100), vec![0]);
+        assert_eq!(tiling.offsets(60), vec![0]);
+        assert_eq!(tiling.offsets(101), vec![0, 1]);
+        assert_eq!(tiling.offsets(180), vec![0, 80]);
+        assert_eq!(tiling.offsets(250), vec![0, 80, 150]);
+    }
+
+    #[test]
+    fn tiles_cover_the_side_when_overlap_exceeds_the_step() {
+        let tiling = tiling(100, 60);
+        assert!(tiling.validate().is_ok());
+        assert_eq!(tiling.offsets(150), vec![0, 40, 50]);
+    }
+
+    #[test]
+    fn rejects_overlap_of_a_whole_tile() {
+        assert!(tiling(100, 100).validate().is_err());
+        assert!(tiling(100, 150).validate().is_err());
+        assert!(tiling(0, 0).validate().is_err());
+    }
+
+    #[test]
+    fn regions_are_clamped_to_small_sides() {
+        let regions = tiling(100, 20).regions(250, 60).unwrap();
+        let expected: Vec<Region> = [0, 80, 150]
+            .into_iter()
+            .map(|x| Region {
+                x,
+                y: 0,
+                width: 100,
+                height: 60,
+            })
+            .collect();
+        assert_eq!(regions, expected);
+    }
+
+    #[test]
+    fn regions_respect_max_tiles() {
+        let tiling = TilingConfig {
+            max_tiles: 4,
+            ..tiling(100, 20)
+        };
+        assert_eq!(tiling.regions(180, 180).unwrap().len(), 4);
+        assert!(tiling.regions(250, 180).is_err());
+    }
+
+    #[test]
+    fn max_tiles_covers_every_frame() {
+        let frames = || {
+            (0..3)
+                .map(|index| DecodedFrame {
+                    index,
+                    image: DynamicImage::new_rgb8(180, 100),
+                })
+                .collect::<Vec<_>>()
+        };
+        let limited = |max_tiles| TilingConfig {
+            max_tiles,
+            ..tiling(100, 20)
+        };
+        assert_eq!(views(frames(), None, Some(limited(6))).unwrap().len(), 6);
+        assert!(views(frames(), None, Some(limited(5))).is_err());
+        assert_eq!(views(frames(), None, None).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn crop_may_touch_the_image_edge() {
+        let crop = Region {
+            x: 150,
+            y: 50,
+            width: 50,
+            height: 50,
+        };
+        assert!(crop.check(200, 100).is_ok());
+        assert!(Region { width: 51, ..crop }.check(200, 100).is_err());
+        assert!(Region { height: 51, ..crop }.check(200, 100).is_err());
+        assert!(Region { width: 0, ..crop }.check(200, 100).is_err());
+        assert!(Region {
+            x: u32::MAX,
+            ..crop
+        }
+        .check(200, 100)
+        .is_err());
+    }
+
+    #[test]
+    fn samples_every_frame_when_asked_for_more() {
+        let sample = |count| FrameSelection::Sample { count };
+        assert_eq!(sample(5).indices(3).unwrap(), vec![0, 1, 2]);
+        assert_eq!(sample(2).indices(5).unwrap(), vec![0, 2]);
+        assert_eq!(sample(1).indices(1).unwrap(), vec![0]);
+        assert!(sample(0).indices(3).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_frames() {
+        assert!(FrameSelection::Frame { index: 3 }.indices(3).is_err());
+        let frames = std::iter::empty();
+        assert!(select_frames(frames, || Ok(0), FrameSelection::default()).is_err());
+        let frames = (0..3).map(|_| Ok(DynamicImage::new_rgb8(1, 1)));
+        let selection = FrameSelection::Frame { index: 3 };
+        assert!(select_frames(frames, || Ok(3), selection).is_err());
+    }
+
+    #[test]
+    fn stops_decoding_at_the_selected_frame() {
+        let decoded = Cell::new(0);
+        let frames = (0..5).map(|_| {
+            decoded.set(decoded.get() + 1);
+            Ok(DynamicImage::new_rgb8(1, 1))
+        });
+        let selection = FrameSelection::Frame { index: 1 };
+        let selected = select_frames(frames, || unreachable!(), selection).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].index, 1);
+        assert_eq!(decoded.get(), 2);
+    }
+
+    #[test]
+    fn keeps_only_the_sampled_frames() {
+        let decoded = Cell::new(0);
+        let frames = (0..5).map(|_| {
+            decoded.set(decoded.get() + 1);
+            Ok(DynamicImage::new_rgb8(1, 1))
+        });
+        let selection = FrameSelection::Sample { count: 2 };
+        let selected = select_frames(frames, || Ok(5), selection).unwrap();
+        let indices: Vec<usize> = selected.iter().map(|frame| frame.index).collect();
+        assert_eq!(indices, vec![0, 2]);
+        assert_eq!(decoded.get(), 3);
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    moondream::{ComputeDtype, ModelDescriptor},
+    preprocess::PreprocessSettings,
+    utils::DeviceSetting,
+    Error,
+};
+
+/// User settings persisted as JSON in the app config dir. The file can also be
+/// edited by hand while the app is closed.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    pub model: ModelDescriptor,
+    /// Hub to download models from, defaults to huggingface.co.
+    pub hub_endpoint: Option<String>,
+    /// Overrides the dtype picked for the device.
+    pub dtype: Option<ComputeDtype>,
+    pub device: DeviceSetting,
+    pub preprocess: PreprocessSettings,
+}
+
+impl Settings {
+    pub fn path(config_dir: &Path) -> PathBuf {
+        config_dir.join("settings.json")
+    }
+
+    /// Reads the settings, falling back to the defaults when the file is missing
+    /// or unreadable.
+    pub fn load(path: &Path) -> Self {
+        if !path.exists() {
+            return Self::default();
+        }
+        let settings: Result<Self, Error> = std::fs::read_to_string(path)
+            .map_err(Error::file(path))
+            .and_then(|settings| Ok(serde_json::from_str(&settings)?));
+        match settings {
+            Ok(settings) => settings,
+            Err(e) => {
+                tracing::error!("Could not read settings {:?}: {:?}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(Error::file(dir))?;
+        }
+        let settings = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, settings).map_err(Error::file(path))
+    }
+}
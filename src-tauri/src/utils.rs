@@ -1,62 +1,263 @@
-use crate::base64img::TEST_IMG;
+use crate::{
+    base64img::TEST_IMG,
+    preprocess::{FrameSelection, PreprocessConfig, Region, TilingConfig},
+    TARGET,
+};
 use base64::{engine::general_purpose, Engine};
-use candle::{utils, Device, Error, Result, Tensor};
-use std::io::Cursor;
+use candle::{utils, Device, DeviceLocation, Error, Result, Tensor};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Where an image comes from, either disk or the frontend, e.g. a clipboard
+/// paste or a webcam frame.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ImageSource {
+    Path {
+        path: PathBuf,
+    },
+    Bytes {
+        data: Vec<u8>,
+    },
+    /// Base64 encoded image, optionally as a `data:` URL.
+    Base64 {
+        data: String,
+    },
+}
+
+/// An image to analyze and which of its frames to look at.
+#[derive(Clone, Deserialize)]
+pub struct ImageInput {
+    #[serde(flatten)]
+    pub source: ImageSource,
+    #[serde(default)]
+    pub frames: FrameSelection,
+    /// Only look at this part of the image.
+    #[serde(default)]
+    pub crop: Option<Region>,
+    #[serde(default)]
+    pub tiling: Option<TilingConfig>,
+}
+
+impl ImageInput {
+    /// The encoded image, read from disk for `Path` inputs.
+    pub fn bytes(&self) -> std::result::Result<Cow<'_, [u8]>, crate::Error> {
+        match &self.source {
+            ImageSource::Path { path } => Ok(Cow::Owned(
+                std::fs::read(path).map_err(crate::Error::file(path))?,
+            )),
+            ImageSource::Bytes { data } => Ok(Cow::Borrowed(data)),
+            ImageSource::Base64 { data } => {
+                Ok(Cow::Owned(decode_base64(data).map_err(|e| {
+                    crate::Error::InputError(format!("Invalid base64 image: {e}"))
+                })?))
+            }
+        }
+    }
+}
+
+/// Short description for logs, without the image data.
+impl fmt::Display for ImageInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            ImageSource::Path { path } => write!(f, "{}", path.display()),
+            ImageSource::Bytes { data } => write!(f, "{} bytes", data.len()),
+            ImageSource::Base64 { data } => write!(f, "{} base64 characters", data.len()),
+        }?;
+        if self.frames != FrameSelection::default() {
+            write!(f, " ({:?})", self.frames)?;
+        }
+        if let Some(crop) = &self.crop {
+            write!(f, " cropped to {crop:?}")?;
+        }
+        if let Some(tiling) = &self.tiling {
+            write!(f, " tiled with {tiling:?}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ImageInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ImageInput({self})")
+    }
+}
+
+/// Decodes plain base64 or a base64 `data:` URL.
+fn decode_base64(data: &str) -> std::result::Result<Vec<u8>, base64::DecodeError> {
+    let data = match data.strip_prefix("data:") {
+        Some(url) => url.split_once(',').map(|(_, data)| data).unwrap_or(url),
+        None => data,
+    };
+    general_purpose::STANDARD.decode(data.trim())
+}
 
 /// Loads an image from disk using the image crate, this returns a tensor with shape
 /// (3, 378, 378).
 pub fn load_image<P: AsRef<std::path::Path>>(p: P) -> Result<Tensor> {
-    let img = image::io::Reader::open(p)?
-        .decode()
-        .map_err(candle::Error::wrap)?
-        .resize_to_fill(378, 378, image::imageops::FilterType::Triangle); // Adjusted to 378x378
-    let img = img.to_rgb8();
-    let data = img.into_raw();
-    let data = Tensor::from_vec(data, (378, 378, 3), &Device::Cpu)?.permute((2, 0, 1))?;
-    let mean = Tensor::new(&[0.5f32, 0.5, 0.5], &Device::Cpu)?.reshape((3, 1, 1))?;
-    let std = Tensor::new(&[0.5f32, 0.5, 0.5], &Device::Cpu)?.reshape((3, 1, 1))?;
-    (data.to_dtype(candle::DType::F32)? / 255.)?
-        .broadcast_sub(&mean)?
-        .broadcast_div(&std)
+    PreprocessConfig::default().apply(&std::fs::read(p)?)
 }
 
 pub fn load_hardcoded_image() -> Result<Tensor> {
-    let img = general_purpose::STANDARD
-        .decode(&*TEST_IMG)
-        .map_err(|err| Error::Msg(err.to_string()))?;
-    let img = image::io::Reader::new(Cursor::new(img))
-        .with_guessed_format()?
-        .decode()
-        .map_err(candle::Error::wrap)?
-        .resize_to_fill(378, 378, image::imageops::FilterType::Triangle); // Adjusted to 378x378
-    let img = img.to_rgb8();
-    let data = img.into_raw();
-    let data = Tensor::from_vec(data, (378, 378, 3), &Device::Cpu)?.permute((2, 0, 1))?;
-    let mean = Tensor::new(&[0.5f32, 0.5, 0.5], &Device::Cpu)?.reshape((3, 1, 1))?;
-    let std = Tensor::new(&[0.5f32, 0.5, 0.5], &Device::Cpu)?.reshape((3, 1, 1))?;
-    (data.to_dtype(candle::DType::F32)? / 255.)?
-        .broadcast_sub(&mean)?
-        .broadcast_div(&std)
-}
-
-pub fn device(cpu: bool) -> Result<Device> {
-    if cpu {
-        Ok(Device::Cpu)
-    } else if utils::cuda_is_available() {
-        Ok(Device::new_cuda(0)?)
-    } else if utils::metal_is_available() {
-        Ok(Device::new_metal(0)?)
-    } else {
-        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
-        {
-            println!(
-                "Running on CPU, to run on GPU(metal), build this example with `--features metal`"
-            );
+    let img = decode_base64(&TEST_IMG).map_err(|err| Error::Msg(err.to_string()))?;
+    PreprocessConfig::default().apply(&img)
+}
+
+/// Device the model runs on, persisted as `auto`, `cpu`, `cuda:N` or `metal`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum DeviceSetting {
+    /// The first GPU available, falling back to the CPU.
+    #[default]
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal,
+}
+
+impl FromStr for DeviceSetting {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(DeviceSetting::Auto),
+            "cpu" => Ok(DeviceSetting::Cpu),
+            "metal" => Ok(DeviceSetting::Metal),
+            "cuda" => Ok(DeviceSetting::Cuda(0)),
+            _ => s
+                .strip_prefix("cuda:")
+                .and_then(|ordinal| ordinal.parse().ok())
+                .map(DeviceSetting::Cuda)
+                .ok_or_else(|| format!("Unknown device {s}")),
+        }
+    }
+}
+
+impl TryFrom<String> for DeviceSetting {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for DeviceSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSetting::Auto => write!(f, "auto"),
+            DeviceSetting::Cpu => write!(f, "cpu"),
+            DeviceSetting::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            DeviceSetting::Metal => write!(f, "metal"),
+        }
+    }
+}
+
+impl From<DeviceSetting> for String {
+    fn from(setting: DeviceSetting) -> Self {
+        setting.to_string()
+    }
+}
+
+impl From<&Device> for DeviceSetting {
+    fn from(device: &Device) -> Self {
+        match device.location() {
+            DeviceLocation::Cpu => DeviceSetting::Cpu,
+            DeviceLocation::Cuda { gpu_id } => DeviceSetting::Cuda(gpu_id),
+            DeviceLocation::Metal { .. } => DeviceSetting::Metal,
         }
-        #[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
-        {
-            println!("Running on CPU, to run on GPU, build this example with `--features cuda`");
+    }
+}
+
+/// SIMD features candle was built with, which decide how fast CPU inference is.
+#[derive(Debug, Clone, Serialize)]
+pub struct CpuFeatures {
+    pub avx: bool,
+    pub neon: bool,
+    pub simd128: bool,
+    pub f16c: bool,
+}
+
+impl CpuFeatures {
+    pub fn detect() -> Self {
+        Self {
+            avx: utils::with_avx(),
+            neon: utils::with_neon(),
+            simd128: utils::with_simd128(),
+            f16c: utils::with_f16c(),
+        }
+    }
+}
+
+fn metal_is_available() -> bool {
+    // Simulator doesn't support MPS (Metal Performance Shader).
+    utils::metal_is_available() && TARGET != "aarch64-apple-ios-sim"
+}
+
+/// Every device that can be selected on this machine.
+pub fn available_devices() -> Vec<DeviceSetting> {
+    let mut devices = vec![DeviceSetting::Cpu];
+    if utils::cuda_is_available() {
+        devices.extend(
+            (0..)
+                .take_while(|&ordinal| Device::new_cuda(ordinal).is_ok())
+                .map(DeviceSetting::Cuda),
+        );
+    }
+    if metal_is_available() {
+        devices.push(DeviceSetting::Metal);
+    }
+    devices
+}
+
+pub fn device(setting: DeviceSetting) -> Result<Device> {
+    match setting {
+        DeviceSetting::Auto if utils::cuda_is_available() => Device::new_cuda(0),
+        DeviceSetting::Auto if metal_is_available() => Device::new_metal(0),
+        DeviceSetting::Auto | DeviceSetting::Cpu => Ok(Device::Cpu),
+        DeviceSetting::Cuda(ordinal) => Device::new_cuda(ordinal),
+        DeviceSetting::Metal => Device::new_metal(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_plain_base64_and_data_urls() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64(" aGVsbG8=\n").unwrap(), b"hello");
+        assert_eq!(
+            decode_base64("data:image/png;base64,aGVsbG8=").unwrap(),
+            b"hello"
+        );
+        assert!(decode_base64("not base64!").is_err());
+        assert!(decode_base64("data:image/png;base64,not base64!").is_err());
+    }
+
+    #[test]
+    fn device_settings_round_trip() {
+        for setting in ["auto", "cpu", "cuda:1", "metal"] {
+            let parsed: DeviceSetting = setting.parse().unwrap();
+            assert_eq!(parsed.to_string(), setting);
+            let json = serde_json::to_string(&parsed).unwrap();
+            assert_eq!(json, format!("\"{setting}\""));
+            assert_eq!(
+                serde_json::from_str::<DeviceSetting>(&json).unwrap(),
+                parsed
+            );
         }
-        Ok(Device::Cpu)
+        // A bare `cuda` is the first GPU.
+        assert_eq!("cuda".parse(), Ok(DeviceSetting::Cuda(0)));
+    }
+
+    #[test]
+    fn rejects_unknown_devices() {
+        assert!("cuda:x".parse::<DeviceSetting>().is_err());
+        assert!("gpu".parse::<DeviceSetting>().is_err());
+        assert!(serde_json::from_str::<DeviceSetting>("\"cuda:x\"").is_err());
     }
 }
@@ -1,16 +1,34 @@
-import { invoke } from "@tauri-apps/api/core";
-import { UnlistenFn, listen } from "@tauri-apps/api/event";
+import { Channel, convertFileSrc, invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import { open } from "@tauri-apps/plugin-dialog";
 import { info, error } from "@tauri-apps/plugin-log";
-import { Payload } from "./types";
+import {
+  ErrorPayload,
+  GenerationEvent,
+  ImageInput,
+  LibraryImage,
+  ModelStatus,
+} from "./types";
 
 let errorMessage: HTMLParagraphElement | null;
 let modelResponse: HTMLParagraphElement | null;
+let modelStatus: HTMLParagraphElement | null;
 let prompt: HTMLInputElement | null;
 let image: HTMLInputElement | null;
 let imagePreview: HTMLImageElement | null;
 let loading = false;
 let isAborted = false;
+let requestId: number | null = null;
+
+function describeError(err: unknown): string {
+  if (err && typeof err === "object" && "message" in err) {
+    const payload = err as ErrorPayload;
+    return payload.details?.path
+      ? `${payload.message} (${payload.details.path})`
+      : payload.message;
+  }
+  return `${err}`;
+}
 
 async function getTextGenerationStream() {
   if (!modelResponse) {
@@ -19,20 +37,13 @@ async function getTextGenerationStream() {
   loading = true;
   modelResponse.textContent = "Loading image and model...";
 
-  let unlisten: Promise<UnlistenFn> = Promise.resolve(() => {});
-  const response = new ReadableStream({
+  const onEvent = new Channel<GenerationEvent>();
+  const response = new ReadableStream<GenerationEvent>({
     start(controller) {
-      unlisten = listen("text-generation", (output: any) => {
-        if (!output) {
-          // If no output is received, consider closing the stream or logging an error
-          info("Received empty output, possible end of data");
-          controller.close();
-          return;
-        }
-        info(`Received output: ${JSON.stringify(output)}`);
-        const data = output.payload as Payload;
+      onEvent.onmessage = (data) => {
+        info(`Received output: ${JSON.stringify(data)}`);
         controller.enqueue(data);
-      });
+      };
     },
     cancel() {
       info("Stream cancelled");
@@ -41,12 +52,13 @@ async function getTextGenerationStream() {
 
   info("Invoking generate");
 
-  await invoke("generate", {
+  requestId = await invoke("generate", {
     prompt: prompt && prompt.value,
-    image: image && image.value,
+    image: { kind: "path", path: image && image.value } as ImageInput,
+    onEvent,
   });
 
-  info("Invoked generate");
+  info(`Invoked generate, request ${requestId}`);
   const reader = response.getReader();
   try {
     while (true) {
@@ -65,24 +77,52 @@ async function getTextGenerationStream() {
       info("Reader value", value);
       if (isAborted) {
         isAborted = false;
-        await invoke("stop");
         break;
       }
 
-      // final message
-      if (value.generated_text) {
+      if (value.event === "queued") {
+        modelResponse.textContent = `Waiting in queue (position ${
+          value.position + 1
+        })...`;
+        loading = true;
+        continue;
+      }
+      if (value.event === "started") {
+        modelResponse.textContent = "Loading image and model...";
+        loading = true;
+        continue;
+      }
+      if (value.event === "cancelled") {
+        break;
+      }
+      if (value.event === "aggregated") {
+        modelResponse.textContent = value.generated_text;
+        break;
+      }
+      if (value.event === "error") {
+        error(`Generation failed (${value.code}): ${value.message}`);
+        errorMessage!.textContent = `Error: ${value.message}`;
         break;
       }
 
       if (!value.token.special) {
         modelResponse.textContent += value.token.text;
       }
+
+      // final message, a failed generation is followed by an error event
+      if (value.details) {
+        info(`Generation details: ${JSON.stringify(value.details)}`);
+        const lastPart = !value.part || value.part.index + 1 === value.part.count;
+        if (value.details.finish_reason !== "error" && lastPart) {
+          break;
+        }
+      }
     }
   } catch (err) {
-    error(`Error: ${err}`);
-    errorMessage!.textContent = `Error: ${err}`;
+    error(`Error: ${describeError(err)}`);
+    errorMessage!.textContent = `Error: ${describeError(err)}`;
   } finally {
-    await unlisten;
+    requestId = null;
     reader.releaseLock();
   }
 }
@@ -92,8 +132,8 @@ async function sendInput() {
     isAborted = false;
     await getTextGenerationStream();
   } catch (err) {
-    error(`Error: ${err}`);
-    errorMessage!.textContent = `Error: ${err}`;
+    error(`Error: ${describeError(err)}`);
+    errorMessage!.textContent = `Error: ${describeError(err)}`;
   }
 }
 
@@ -104,35 +144,91 @@ async function openImage() {
     result = await open({
       directory: false,
       multiple: false,
-      filter: [{ name: "Images", extensions: ["jpg", "png", "jpeg"] }],
+      filter: [{ name: "Images", extensions: [
+          "jpg",
+          "jpeg",
+          "png",
+          "webp",
+          "gif",
+          "bmp",
+          "tif",
+          "tiff",
+        ], }],
     });
   } catch (err) {
-    errorMessage!.textContent = `Error: ${err}`;
+    errorMessage!.textContent = `Error: ${describeError(err)}`;
     return;
   }
 
   if (result && image) {
     try {
-      const imagePath: string = await invoke("copy_image", {
+      const imported: LibraryImage = await invoke("import_image", {
         src: result.path,
       });
-      // split iamge path to get the file name
-      imagePreview!.src = `./assets/${imagePath.split("/").pop()}`;
-      image.value = imagePath;
+      imagePreview!.src = convertFileSrc(imported.path);
+      image.value = imported.path;
     } catch (err) {
-      errorMessage!.textContent = `Error: ${err}`;
+      errorMessage!.textContent = `Error: ${describeError(err)}`;
       return;
     }
   }
 }
 
+function showModelStatus(status: ModelStatus) {
+  if (!modelStatus) {
+    return;
+  }
+  switch (status.status) {
+    case "not_loaded":
+      modelStatus.textContent = "Model not loaded";
+      break;
+    case "not_downloaded":
+      modelStatus.textContent = "Model not downloaded";
+      break;
+    case "downloading":
+      modelStatus.textContent = `Downloading ${status.file} (${Math.round(
+        status.progress * 100
+      )}%)`;
+      break;
+    case "loading":
+      modelStatus.textContent = `${status.message} (${Math.round(
+        status.progress * 100
+      )}%)`;
+      break;
+    case "ready":
+      modelStatus.textContent = "Model ready";
+      break;
+    case "failed":
+      modelStatus.textContent = `Model failed to load: ${status.error}`;
+      break;
+  }
+}
+
+async function loadModel() {
+  listen("model-status", (event) => {
+    showModelStatus(event.payload as ModelStatus);
+  });
+  try {
+    const status: ModelStatus = await invoke("model_status");
+    showModelStatus(status);
+    if (status.status === "not_downloaded") {
+      await invoke("download_model");
+    }
+    showModelStatus(await invoke("load_model"));
+  } catch (err) {
+    errorMessage!.textContent = `Error: ${describeError(err)}`;
+  }
+}
+
 async function stop() {
   try {
     modelResponse!.textContent = "";
-    await invoke("stop");
+    if (requestId !== null) {
+      await invoke("stop", { requestId });
+    }
     isAborted = true;
   } catch (err) {
-    errorMessage!.textContent = `Error: ${err}`;
+    errorMessage!.textContent = `Error: ${describeError(err)}`;
   }
 }
 
@@ -142,6 +238,9 @@ window.addEventListener("DOMContentLoaded", () => {
   modelResponse = document.querySelector("#response");
   errorMessage = document.querySelector("#error-message");
   imagePreview = document.querySelector("#image-preview");
+  modelStatus = document.querySelector("#model-status");
+
+  loadModel();
 
   document.querySelector("#image-upload")?.addEventListener("click", () => {
     openImage();
@@ -4,8 +4,202 @@ export interface Token {
   special: boolean;
 }
 
+export type FinishReason =
+  | "eos"
+  | "length"
+  | "stop_sequence"
+  | "cancelled"
+  | "error";
+
+export interface GenerationDetails {
+  finish_reason: FinishReason;
+  prompt_tokens: number;
+  generated_tokens: number;
+  time_to_first_token_ms?: number;
+  vision_encode_ms: number;
+  tokens_per_second: number;
+}
+
+export interface ImagePart {
+  frame: number;
+  tile?: Region;
+  index: number;
+  count: number;
+}
+
 export interface Payload {
   token: Token;
   generated_text?: string;
-  details?: boolean;
+  details?: GenerationDetails;
+  part?: ImagePart;
+}
+
+export interface ErrorDetails {
+  path?: string;
+  url?: string;
+  image_id?: string;
+  model_id?: string;
+  token?: string;
+  files?: string[];
+  request_id?: number;
+}
+
+export interface ErrorPayload {
+  code: string;
+  message: string;
+  details?: ErrorDetails;
+  retryable: boolean;
+}
+
+export type GenerationEvent =
+  | { event: "queued"; position: number }
+  | { event: "started" }
+  | ({ event: "generation" } & Payload)
+  | {
+      event: "aggregated";
+      generated_text: string;
+      answers: { part: ImagePart; answer: string }[];
+    }
+  | { event: "cancelled" }
+  | ({ event: "error" } & ErrorPayload);
+
+export type ModelStatus =
+  | { status: "not_loaded" }
+  | { status: "not_downloaded"; missing: string[] }
+  | { status: "downloading"; file: string; progress: number }
+  | { status: "loading"; message: string; progress: number }
+  | { status: "ready" }
+  | { status: "failed"; error: string };
+
+export interface DownloadProgress {
+  file: string;
+  downloaded: number;
+  total?: number;
+  file_index: number;
+  file_count: number;
+}
+
+export interface Turn {
+  question: string;
+  answer: string;
+}
+
+export interface SamplingConfig {
+  temperature?: number;
+  top_p?: number;
+  top_k?: number;
+  seed?: number;
+  repeat_penalty?: number;
+  repeat_last_n?: number;
+}
+
+export interface StoppingConfig {
+  max_new_tokens?: number;
+  stop?: string[];
+}
+
+export interface JobInfo {
+  id: number;
+  kind: "generate" | "ask" | "batch";
+  prompt: string;
+  status: "queued" | "running";
+  position?: number;
 }
+
+export type ModelSource =
+  | { kind: "hub" }
+  | { kind: "local"; dir: string }
+  | { kind: "bundled"; dir: string };
+
+export interface ModelDescriptor {
+  source: ModelSource;
+  repo_id: string;
+  revision?: string;
+  weights_file: string;
+  weights_format: "safetensors" | "gguf";
+  tokenizer_file: string;
+  config: "v2";
+}
+
+export type ComputeDtype = "f32" | "bf16" | "f16";
+
+// "auto", "cpu", "cuda:N" or "metal".
+export type DeviceSetting = string;
+
+export interface DeviceList {
+  available: DeviceSetting[];
+  selected: DeviceSetting;
+  active: DeviceSetting;
+  cpu: { avx: boolean; neon: boolean; simd128: boolean; f16c: boolean };
+}
+
+export interface LibraryImage {
+  id: string;
+  name: string;
+  path: string;
+  thumbnail: string;
+}
+
+export interface Region {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+export interface TilingConfig {
+  tile_size?: number;
+  overlap?: number;
+  max_tiles?: number;
+  aggregate?: boolean;
+}
+
+export type FrameSelection =
+  | { mode: "frame"; index: number }
+  | { mode: "sample"; count: number };
+
+export type ImageInput = (
+  | { kind: "path"; path: string }
+  | { kind: "bytes"; data: number[] }
+  // Plain base64 or a data URL.
+  | { kind: "base64"; data: string }
+) & {
+  frames?: FrameSelection;
+  crop?: Region;
+  tiling?: TilingConfig;
+};
+
+export interface PreprocessSettings {
+  resize?: "fill" | "letterbox" | "stretch";
+  filter?: "nearest" | "triangle" | "catmull_rom" | "gaussian" | "lanczos3";
+}
+
+export interface BatchRequest {
+  paths?: string[];
+  glob?: string;
+  prompts: string[];
+  output?: { path: string; format: "jsonl" | "csv" };
+  sampling?: SamplingConfig;
+  stopping?: StoppingConfig;
+  // Images encoded and answered together, 1 by default.
+  batch_size?: number;
+}
+
+export interface BatchResult {
+  image: string;
+  prompt: string;
+  answer?: string;
+  error?: ErrorPayload;
+  details?: GenerationDetails;
+}
+
+export type BatchEvent =
+  | { event: "queued"; position: number }
+  | { event: "started" }
+  | { event: "progress"; done: number; total: number }
+  | ({ event: "result" } & BatchResult)
+  | { event: "paused" }
+  | { event: "resumed" }
+  | { event: "finished"; done: number; failed: number }
+  | { event: "cancelled" }
+  | ({ event: "error" } & ErrorPayload);